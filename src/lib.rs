@@ -0,0 +1,2377 @@
+//! Task lists with selectors for picking tasks and storage in CSV files.
+
+use std::{collections::{HashMap, HashSet}, fmt::{Display, Formatter}, path::Path, fs, io::{self, Read, Write}};
+
+use chrono::{DateTime, Datelike, Duration, Local, FixedOffset, NaiveDate, NaiveDateTime, TimeZone, Weekday};
+use flate2::{Compression, read::GzDecoder, write::GzEncoder};
+use regex::Regex;
+use serde_json::json;
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
+#[derive(Debug)]
+pub enum TaskError {
+    NotFound,
+    CompletedBeforeCreated,
+}
+
+impl Display for TaskError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", match self {
+            TaskError::NotFound => "Task not found",
+            TaskError::CompletedBeforeCreated => "Task can't be completed before it was created",
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct Task {
+    /// Number identifying the task, which doesn't change when the list is
+    /// reordered. Zero until the task is added to a list.
+    pub id: u32,
+    pub text: String,
+    pub created: DateTime<FixedOffset>,
+    pub completed: Option<DateTime<FixedOffset>>,
+    pub labels: Vec<String>,
+    /// Importance of the task from zero (none) to `MAX_PRIORITY`.
+    pub priority: u8,
+    /// Previous texts of the task, oldest first. Only kept if requested when
+    /// rewording.
+    pub history: Vec<String>,
+    pub due: Option<DateTime<FixedOffset>>,
+    /// When work on the task was started with `Tasks::work_on`.
+    pub started: Option<DateTime<FixedOffset>>,
+}
+
+impl Task {
+    pub fn new(text: String, created: DateTime<FixedOffset>) -> Task {
+        Task{id: 0, text, created, completed: None, labels: vec![], priority: 0, history: vec![], due: None, started: None}
+    }
+
+    pub fn from_string(string: String) -> Task {
+        Task::new(string, DateTime::from(Local::now()))
+    }
+
+    /// Serializes the task. Besides the stored fields, `age_days` and
+    /// `is_overdue` are derived from the current time; `is_overdue` is null
+    /// for tasks without due date.
+    pub fn to_json(&self) -> serde_json::Value {
+        let now = DateTime::from(Local::now());
+        json!({
+            "id": self.id,
+            "text": self.text,
+            "created": self.created.format(TIME_FORMAT).to_string(),
+            "completed": self.completed.map(|time| time.format(TIME_FORMAT).to_string()),
+            "labels": self.labels,
+            "priority": self.priority,
+            "history": self.history,
+            "due": self.due.map(|time| time.format(TIME_FORMAT).to_string()),
+            "started": self.started.map(|time| time.format(TIME_FORMAT).to_string()),
+            "age_days": self.age(now).num_days(),
+            "is_overdue": self.due.map(|due| self.is_active() && due < now),
+        })
+    }
+
+    /// Returns whether the task was completed.
+    pub fn is_completed(&self) -> bool {
+        self.completed.is_some()
+    }
+
+    /// Returns whether the task is still unfinished.
+    pub fn is_active(&self) -> bool {
+        self.completed.is_none()
+    }
+
+    /// Returns how long ago the task was created.
+    pub fn age(&self, now: DateTime<FixedOffset>) -> Duration {
+        now.signed_duration_since(self.created)
+    }
+
+    /// Returns the words of the text which are tags, like `@work` or `+review`.
+    pub fn tags(&self) -> impl Iterator<Item = &str> {
+        self.text.split_whitespace()
+            .filter(|word| word.len() > 1 && word.starts_with(['@', '+']))
+    }
+
+    /// Returns a single field of the task as text, or `None` if there is no
+    /// field with that name. See `FIELDS` for the names.
+    pub fn field(&self, name: &str) -> Option<String> {
+        Some(match name {
+            "id" => self.id.to_string(),
+            "text" => self.text.clone(),
+            "created" => self.created.format(TIME_FORMAT).to_string(),
+            "completed" => self.completed.map(|time| time.format(TIME_FORMAT).to_string()).unwrap_or_default(),
+            "started" => self.started.map(|time| time.format(TIME_FORMAT).to_string()).unwrap_or_default(),
+            "labels" => self.labels.join(LABEL_SEPARATOR),
+            "tags" => self.tags().collect::<Vec<_>>().join(" "),
+            "priority" => self.priority.to_string(),
+            "due" => self.due.map(|time| time.format(TIME_FORMAT).to_string()).unwrap_or_default(),
+            "age" => self.age(DateTime::from(Local::now())).num_days().to_string(),
+            _ => return None,
+        })
+    }
+}
+
+impl Display for Task {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.is_active() {
+            true => write!(f, "{}", self.text)?,
+            false => write!(f, "X {}", self.text)?,
+        }
+        if !self.labels.is_empty() {
+            write!(f, " [{}]", self.labels.join(", "))?;
+        }
+        if let Some(due) = self.due {
+            write!(f, " (due {})", due.format(DUE_FORMAT))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct Tasks {
+    pub tasks: Vec<Task>,
+    /// Whether the tasks were changed by one of the methods since they were
+    /// loaded, so they have to be saved.
+    pub dirty: bool,
+}
+
+impl FromIterator<Task> for Tasks {
+    fn from_iter<I: IntoIterator<Item = Task>>(iter: I) -> Self {
+        Tasks{tasks: iter.into_iter().collect(), dirty: false}
+    }
+}
+
+impl IntoIterator for Tasks {
+    type Item = Task;
+    type IntoIter = std::vec::IntoIter<Task>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.tasks.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a Tasks {
+    type Item = &'a Task;
+    type IntoIter = std::slice::Iter<'a, Task>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.tasks.iter()
+    }
+}
+
+/// Difference of a task between two versions of a list.
+pub enum Change<'a> {
+    Added(&'a Task),
+    Removed(&'a Task),
+    Changed(&'a Task, &'a Task),
+}
+
+impl Display for Change<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Change::Added(task) => write!(f, "+ {}", task),
+            Change::Removed(task) => write!(f, "- {}", task),
+            Change::Changed(old, new) => write!(f, "~ {} -> {}", old, new),
+        }
+    }
+}
+
+pub trait TaskSelector {
+    fn matches(&self, tasks: &Tasks, index: usize) -> bool;
+
+    /// Describes what the selector matches, like `pattern 'bug'`.
+    fn describe(&self) -> String;
+
+    /// Checks that the selector can match anything in the list, to tell a
+    /// missing id apart from a number that is out of range.
+    fn validate(&self, _tasks: &Tasks) -> Result<(), SelectorError> {
+        Ok(())
+    }
+
+    /// Explains why the task matches, or returns `None` if it doesn't.
+    fn explain(&self, tasks: &Tasks, index: usize) -> Option<String> {
+        self.matches(tasks, index).then(|| self.describe())
+    }
+}
+
+pub struct AllSelector {}
+
+pub struct PatternSelector {
+    pub pattern: Regex
+}
+
+pub struct RangeSelector {
+    pub from: usize,
+    pub to: usize,
+}
+
+pub struct IndexSelector {
+    pub index: usize
+}
+
+/// Matches the task with the given stable id, written as `#42`.
+pub struct IdSelector {
+    pub id: u32
+}
+
+#[derive(Debug)]
+pub enum SelectorError {
+    UnknownId(u32),
+    OutOfRange(usize),
+    InvalidPattern(String),
+}
+
+impl Display for SelectorError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SelectorError::UnknownId(id) => write!(f, "No task with id #{}", id),
+            SelectorError::OutOfRange(number) => write!(f, "No task number {}", number),
+            SelectorError::InvalidPattern(pattern) => write!(f, "Invalid pattern {}", pattern),
+        }
+    }
+}
+
+pub struct LabelSelector {
+    pub label: String
+}
+
+/// Error returned by `Tasks::set`.
+pub enum SetError {
+    UnknownField,
+    /// The value doesn't fit the field, which expects the described value.
+    InvalidValue(&'static str),
+    Task(TaskError),
+}
+
+impl Display for SetError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SetError::UnknownField => write!(f, "Unknown field, expected one of {}", SETTABLE_FIELDS.join(", ")),
+            SetError::InvalidValue(expected) => write!(f, "Invalid value, expected {}", expected),
+            SetError::Task(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+/// Fields which can be changed with `Tasks::set`.
+pub const SETTABLE_FIELDS: [&str; 5] = ["text", "created", "completed", "priority", "due"];
+
+/// Matches either completed or unfinished tasks.
+pub struct CompletedSelector {
+    pub completed: bool
+}
+
+/// Matches tasks completed at or after `start` and before `end`.
+pub struct CompletedBetweenSelector {
+    pub start: DateTime<FixedOffset>,
+    pub end: DateTime<FixedOffset>,
+}
+
+/// Matches tasks with or without tags or labels.
+pub struct TaggedSelector {
+    pub tagged: bool
+}
+
+/// Matches tasks containing the characters of the query in order, like fzf.
+pub struct FuzzySelector {
+    pub query: String,
+    /// Tasks scoring lower than this don't match.
+    pub min_score: u32,
+}
+
+/// Matches tasks that are matched by all of the selectors.
+pub struct AndSelector {
+    pub selectors: Vec<Box<dyn TaskSelector>>
+}
+
+/// Matches tasks matched by any of the selectors.
+pub struct OrSelector {
+    pub selectors: Vec<Box<dyn TaskSelector>>
+}
+
+impl TaskSelector for PatternSelector {
+    fn matches(&self, tasks: &Tasks, index: usize) -> bool {
+        if let Some(content) = &tasks.tasks.get(index) {
+            return self.pattern.is_match(&content.text)
+        }
+        false
+    }
+
+    fn describe(&self) -> String {
+        format!("pattern '{}'", self.pattern)
+    }
+}
+
+impl TaskSelector for IndexSelector {
+    fn matches(&self, _tasks: &Tasks, index: usize) -> bool {
+        index == self.index
+    }
+
+    fn validate(&self, tasks: &Tasks) -> Result<(), SelectorError> {
+        match self.index < tasks.tasks.len() {
+            true => Ok(()),
+            false => Err(SelectorError::OutOfRange(self.index + 1)),
+        }
+    }
+
+    fn describe(&self) -> String {
+        format!("number {}", self.index + 1)
+    }
+}
+
+impl TaskSelector for IdSelector {
+    fn matches(&self, tasks: &Tasks, index: usize) -> bool {
+        tasks.tasks.get(index).is_some_and(|task| task.id == self.id)
+    }
+
+    fn validate(&self, tasks: &Tasks) -> Result<(), SelectorError> {
+        match tasks.find_id(self.id) {
+            Some(_) => Ok(()),
+            None => Err(SelectorError::UnknownId(self.id)),
+        }
+    }
+
+    fn describe(&self) -> String {
+        format!("id #{}", self.id)
+    }
+}
+
+impl TaskSelector for RangeSelector {
+    fn matches(&self, _tasks: &Tasks, index: usize) -> bool {
+        index >= self.from && index <= self.to
+    }
+
+    fn validate(&self, tasks: &Tasks) -> Result<(), SelectorError> {
+        match self.from < tasks.tasks.len() {
+            true => Ok(()),
+            false => Err(SelectorError::OutOfRange(self.from + 1)),
+        }
+    }
+
+    fn describe(&self) -> String {
+        format!("range {}-{}", self.from + 1, self.to + 1)
+    }
+}
+
+impl TaskSelector for LabelSelector {
+    fn matches(&self, tasks: &Tasks, index: usize) -> bool {
+        if let Some(content) = &tasks.tasks.get(index) {
+            return content.labels.contains(&self.label)
+        }
+        false
+    }
+
+    fn describe(&self) -> String {
+        format!("label '{}'", self.label)
+    }
+}
+
+impl FuzzySelector {
+    /// Creates a selector which requires about half of the query to match
+    /// consecutively or at the start of words.
+    pub fn new(query: &str) -> Self {
+        Self{query: query.to_lowercase(), min_score: 2 * query.chars().count() as u32}
+    }
+
+    /// Scores how well the text matches, or returns `None` if the characters
+    /// of the query don't all appear in order. Every matched character counts
+    /// one point, with a bonus of two for following the previous match and
+    /// two for starting a word.
+    pub fn score(&self, text: &str) -> Option<u32> {
+        let mut query = self.query.chars().peekable();
+        let mut score = 0;
+        let mut previous_matched = false;
+        let mut previous = ' ';
+        for character in text.to_lowercase().chars() {
+            let matched = query.peek() == Some(&character);
+            if matched {
+                query.next();
+                score += 1;
+                if previous_matched {
+                    score += 2;
+                }
+                if !previous.is_alphanumeric() {
+                    score += 2;
+                }
+            }
+            previous_matched = matched;
+            previous = character;
+        }
+        query.peek().is_none().then_some(score)
+    }
+}
+
+impl TaskSelector for FuzzySelector {
+    fn matches(&self, tasks: &Tasks, index: usize) -> bool {
+        tasks.tasks.get(index)
+            .and_then(|task| self.score(&task.text))
+            .is_some_and(|score| score >= self.min_score)
+    }
+
+    fn describe(&self) -> String {
+        format!("fuzzy '{}'", self.query)
+    }
+}
+
+impl TaskSelector for AndSelector {
+    fn matches(&self, tasks: &Tasks, index: usize) -> bool {
+        self.selectors.iter().all(|selector| selector.matches(tasks, index))
+    }
+
+    fn validate(&self, tasks: &Tasks) -> Result<(), SelectorError> {
+        self.selectors.iter().try_for_each(|selector| selector.validate(tasks))
+    }
+
+    fn describe(&self) -> String {
+        self.selectors.iter().map(|selector| selector.describe()).collect::<Vec<_>>().join(" and ")
+    }
+
+    fn explain(&self, tasks: &Tasks, index: usize) -> Option<String> {
+        let reasons = self.selectors.iter()
+            .map(|selector| selector.explain(tasks, index))
+            .collect::<Option<Vec<_>>>()?;
+        Some(reasons.join(" and "))
+    }
+}
+
+impl TaskSelector for OrSelector {
+    fn matches(&self, tasks: &Tasks, index: usize) -> bool {
+        self.selectors.iter().any(|selector| selector.matches(tasks, index))
+    }
+
+    fn validate(&self, tasks: &Tasks) -> Result<(), SelectorError> {
+        self.selectors.iter().try_for_each(|selector| selector.validate(tasks))
+    }
+
+    fn describe(&self) -> String {
+        self.selectors.iter().map(|selector| selector.describe()).collect::<Vec<_>>().join(" or ")
+    }
+
+    fn explain(&self, tasks: &Tasks, index: usize) -> Option<String> {
+        let reasons: Vec<String> = self.selectors.iter()
+            .filter_map(|selector| selector.explain(tasks, index))
+            .collect();
+        (!reasons.is_empty()).then(|| reasons.join(" or "))
+    }
+}
+
+impl TaskSelector for CompletedSelector {
+    fn matches(&self, tasks: &Tasks, index: usize) -> bool {
+        tasks.tasks.get(index).is_some_and(|task| task.is_completed() == self.completed)
+    }
+
+    fn describe(&self) -> String {
+        match self.completed {
+            true => "completed".to_string(),
+            false => "unfinished".to_string(),
+        }
+    }
+}
+
+impl TaskSelector for CompletedBetweenSelector {
+    fn matches(&self, tasks: &Tasks, index: usize) -> bool {
+        tasks.tasks.get(index).and_then(|task| task.completed)
+            .is_some_and(|completed| completed >= self.start && completed < self.end)
+    }
+
+    fn describe(&self) -> String {
+        format!("completed between {} and {}", self.start.format(TIME_FORMAT), self.end.format(TIME_FORMAT))
+    }
+}
+
+impl TaskSelector for TaggedSelector {
+    fn matches(&self, tasks: &Tasks, index: usize) -> bool {
+        tasks.tasks.get(index).is_some_and(|task|
+            (!task.labels.is_empty() || task.tags().next().is_some()) == self.tagged)
+    }
+
+    fn describe(&self) -> String {
+        match self.tagged {
+            true => "tagged".to_string(),
+            false => "untagged".to_string(),
+        }
+    }
+}
+
+impl TaskSelector for AllSelector {
+    fn matches(&self, _tasks: &Tasks, _index: usize) -> bool {
+        true
+    }
+
+    fn describe(&self) -> String {
+        "all tasks".to_string()
+    }
+}
+
+/// Matches the first unfinished task, which is the one added or worked on
+/// last. Completed tasks stay in place, so that isn't always the first task.
+pub struct FirstActiveSelector {}
+
+impl TaskSelector for FirstActiveSelector {
+    fn matches(&self, tasks: &Tasks, index: usize) -> bool {
+        tasks.tasks.get(index).is_some_and(Task::is_active)
+            && tasks.tasks[..index].iter().all(Task::is_completed)
+    }
+
+    fn describe(&self) -> String {
+        "the first unfinished task".to_string()
+    }
+}
+
+pub enum EmptyBehaviour {
+    /// Select the first unfinished task.
+    SelectLast,
+    SelectAll,
+}
+
+#[derive(Clone, Copy)]
+pub enum DoneHandling {
+    Show,
+    Hide,
+    /// Only include completed tasks.
+    Only,
+}
+
+pub enum OutputFormat {
+    Text,
+    Json,
+    JsonLines,
+    /// Tab-separated id, state, creation time and text, one task per line.
+    /// Unlike `Text`, this format stays compatible between versions.
+    Porcelain,
+}
+
+/// Order in which tasks can be listed.
+#[derive(Clone, Copy)]
+pub enum SortKey {
+    /// Oldest first.
+    Created,
+    Text,
+    /// Highest priority first.
+    Priority,
+    Id,
+    /// Soonest due date first, tasks without one last.
+    Due,
+}
+
+impl SortKey {
+    pub fn parse(string: &str) -> Option<SortKey> {
+        match string {
+            "created" => Some(SortKey::Created),
+            "text" => Some(SortKey::Text),
+            "priority" => Some(SortKey::Priority),
+            "id" => Some(SortKey::Id),
+            "due" => Some(SortKey::Due),
+            _ => None,
+        }
+    }
+}
+
+/// Settings controlling how tasks are printed.
+pub struct View {
+    pub format: OutputFormat,
+    /// Maximum number of terminal columns per line, including the number.
+    pub truncate: Option<usize>,
+    /// Only print the task text, without numbers or headers.
+    pub plain: bool,
+    /// Print the number of each task in front of it.
+    pub numbered: bool,
+    /// Leave out hints meant for new users.
+    pub quiet: bool,
+    /// Show the creation and completion times in the format of the task file.
+    pub raw_time: bool,
+    /// Color unfinished tasks past their due date red, and tasks due within
+    /// this time yellow.
+    pub highlight_due: Option<Duration>,
+    /// Mark unfinished tasks older than this many days with `~`.
+    pub stale_days: Option<i64>,
+    /// Name of the list, shown before listings with `[name]`.
+    pub context: Option<String>,
+    /// Indent JSON output for reading it.
+    pub pretty: bool,
+    /// Lay the status out in columns to fit this many terminal columns.
+    pub columns: Option<usize>,
+}
+
+/// Shortens the text to at most `width` terminal columns, marking the cut with
+/// an ellipsis. Wide characters like CJK count as two columns.
+fn truncate(text: &str, width: usize) -> String {
+    if text.width() <= width {
+        return text.to_string();
+    }
+    let mut clipped = String::new();
+    // Leave room for the ellipsis.
+    let mut used = 1;
+    for character in text.chars() {
+        used += character.width().unwrap_or(0);
+        if used > width {
+            break;
+        }
+        clipped.push(character);
+    }
+    clipped.push('…');
+    clipped
+}
+
+/// Converts a number in the list, which starts at 1, to an index.
+fn number_to_index(number: u32) -> Result<usize, SelectorError> {
+    (number as usize).checked_sub(1).ok_or(SelectorError::OutOfRange(0))
+}
+
+/// Parses a selector given by the user. Fails for numbers which can never
+/// refer to a task, like `0`, and invalid regular expressions.
+pub fn selector_from_string(string: &str, empty: EmptyBehaviour) -> Result<Box<dyn TaskSelector>, SelectorError> {
+    if string.is_empty() {
+        match empty {
+            EmptyBehaviour::SelectLast => return Ok(Box::new(FirstActiveSelector{})),
+            EmptyBehaviour::SelectAll => return Ok(Box::new(AllSelector{}))
+        }
+    }
+    // A leading # always means an id, a bare number a position in the list.
+    if let Some(Ok(id)) = string.strip_prefix('#').map(str::parse::<u32>) {
+        return Ok(Box::new(IdSelector{id}));
+    }
+    if let Ok(number) = string.parse::<u32>() {
+        return Ok(Box::new(IndexSelector{index: number_to_index(number)?}));
+    }
+    if let Some((a, b)) = string.split_once("-") {
+        if let (Ok(a), Ok(b)) = (a.parse::<u32>(), b.parse::<u32>()) {
+            return Ok(Box::new(RangeSelector{from: number_to_index(a)?, to: number_to_index(b)?}));
+        }
+    }
+    // Lists like 1,3,5-7 select each of the numbers, ranges or ids.
+    let is_index = |part: &str| part.trim_start_matches('#').split('-').all(|number| number.parse::<u32>().is_ok());
+    if string.contains(',') && string.split(',').map(str::trim).all(is_index) {
+        return Ok(Box::new(OrSelector{selectors: string.split(',')
+            .map(|part| selector_from_string(part.trim(), EmptyBehaviour::SelectAll))
+            .collect::<Result<_, _>>()?}));
+    }
+    if let Some(query) = string.strip_prefix('~') {
+        return Ok(Box::new(FuzzySelector::new(query)));
+    }
+    let pattern = Regex::new(string).map_err(|_| SelectorError::InvalidPattern(string.to_string()))?;
+    Ok(Box::new(PatternSelector{pattern}))
+}
+
+/// Returns whether the selector is a pattern that matches almost any text,
+/// like `.` or `.*`, which is usually a mistake when changing tasks.
+pub fn is_overbroad(selector: &str) -> bool {
+    if selector.is_empty() || selector.starts_with(['~', '#', ':'])
+            || selector.split(['-', ',']).all(|part| part.trim().parse::<u32>().is_ok()) {
+        return false;
+    }
+    // Patterns matching empty or arbitrary single characters match nearly
+    // every task.
+    Regex::new(selector).is_ok_and(|pattern| pattern.is_match("")
+        || ["a", "Z", "7", " ", "-", "é"].iter().all(|text| pattern.is_match(text)))
+}
+
+const EDIT_HELP: &str = "\
+# Edit, reorder or delete the tasks below, then save and close the file.
+# Mark tasks as done using [x]. Lines starting with # are ignored.
+# Line breaks in tasks are written as \\n and backslashes as \\\\.
+";
+
+/// Removes control characters like escape sequences, which could mess up the
+/// terminal when the task is shown. Tabs and line breaks are kept.
+fn strip_control(text: &str) -> String {
+    text.chars().filter(|character| !character.is_control() || matches!(character, '\t' | '\n')).collect()
+}
+
+/// Writes the text on a single line for the editable list.
+fn escape_line(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+/// Reverses `escape_line`. Backslashes before other characters are kept.
+fn unescape_line(text: &str) -> String {
+    let mut unescaped = String::new();
+    let mut characters = text.chars();
+    while let Some(character) = characters.next() {
+        match (character, characters.clone().next()) {
+            ('\\', Some('n')) => unescaped.push('\n'),
+            ('\\', Some('\\')) => unescaped.push('\\'),
+            _ => {
+                unescaped.push(character);
+                continue;
+            }
+        }
+        characters.next();
+    }
+    unescaped
+}
+
+/// Settings for reading and writing task files.
+pub struct FileSettings {
+    /// Fail on malformed tasks instead of skipping them.
+    pub strict: bool,
+    pub delimiter: u8,
+    /// Write the format marker and the header row. Files are read correctly
+    /// either way.
+    pub header: bool,
+    /// Show how many tasks were read or written on stderr for large files.
+    pub progress: bool,
+    /// Describe what is read and written on stderr.
+    pub verbose: bool,
+}
+
+/// Number of tasks after which progress is shown, and how often it is updated.
+const PROGRESS_INTERVAL: usize = 10000;
+
+/// Prints how many tasks were processed every `PROGRESS_INTERVAL` tasks,
+/// overwriting the previous count.
+fn report_progress(settings: &FileSettings, action: &str, count: usize) {
+    if settings.progress && count > 0 && count.is_multiple_of(PROGRESS_INTERVAL) {
+        eprint!("\r{} tasks... {}", action, count);
+    }
+}
+
+/// Removes the progress output again once done.
+fn finish_progress(settings: &FileSettings, count: usize) {
+    if settings.progress && count >= PROGRESS_INTERVAL {
+        eprint!("\r\x1b[K");
+    }
+}
+
+/// Parses a column delimiter, which has to be a single ASCII character or
+/// "tab".
+pub fn parse_delimiter(string: &str) -> Option<u8> {
+    match string {
+        "tab" | "\\t" => Some(b'\t'),
+        _ if string.len() == 1 && string.is_ascii() => Some(string.as_bytes()[0]),
+        _ => None,
+    }
+}
+
+/// Errors in a task list edited using `td edit --editor`, with the line number.
+#[derive(Debug)]
+pub enum EditError {
+    InvalidLine(usize),
+    UnknownId(usize),
+    DuplicateId(usize),
+}
+
+impl Display for EditError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EditError::InvalidLine(line) => write!(f, "Invalid task on line {}", line),
+            EditError::UnknownId(line) => write!(f, "Unknown task id on line {}", line),
+            EditError::DuplicateId(line) => write!(f, "Task on line {} appears twice", line),
+        }
+    }
+}
+
+/// Errors when reading or writing a task file. Line numbers start at one.
+#[derive(Debug)]
+pub enum TaskFileError {
+    NotFound,
+    MissingColumn(u64),
+    InvalidFormat,
+    InvalidRecord(u64),
+    ParseColmn(u64, &'static str),
+    WriteColumn,
+    Compression,
+    Read,
+}
+
+impl Display for TaskFileError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TaskFileError::NotFound => write!(f, "Task file not found"),
+            TaskFileError::MissingColumn(line) => write!(f, "Task file missing column on line {}", line),
+            TaskFileError::InvalidFormat => write!(f, "Invalid task file format marker"),
+            TaskFileError::InvalidRecord(line) => write!(f, "Invalid task on line {}", line),
+            TaskFileError::ParseColmn(line, field) => write!(f, "Failed to parse {} of task on line {}", field, line),
+            TaskFileError::WriteColumn => write!(f, "Failed to write task"),
+            TaskFileError::Compression => write!(f, "Invalid compressed task file"),
+            TaskFileError::Read => write!(f, "Failed to read tasks"),
+        }
+    }
+}
+
+/// Version of the task file format written by `save`. Version 1 files don't
+/// have a format marker and may lack the labels column, version 2 files lack
+/// the id column, version 3 files lack the priority column, version 4 files
+/// lack the history column, version 5 files lack the due column and version 6
+/// files lack the started column.
+const FORMAT_VERSION: u32 = 7;
+const FORMAT_MARKER: &str = "# td-format: ";
+/// Names of the columns in the header row of task files.
+const HEADER: [&str; 9] = ["text", "created", "completed", "labels", "id", "priority", "history", "due", "started"];
+/// Version of the JSON format in `Tasks::to_json_envelope`.
+const JSON_VERSION: u32 = 1;
+pub const TIME_FORMAT: &str = "%+";
+/// Terminal colors of tasks with due dates.
+const RED: &str = "\x1b[31m";
+const YELLOW: &str = "\x1b[33m";
+const RESET: &str = "\x1b[0m";
+/// Format of due dates shown in listings.
+const DUE_FORMAT: &str = "%Y-%m-%d";
+/// Formats accepted for dates and times given on the command line, in
+/// addition to `TIME_FORMAT`. They are interpreted in the local timezone.
+const INPUT_TIME_FORMATS: [&str; 4] = ["%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M", "%Y-%m-%dT%H:%M"];
+const INPUT_DATE_FORMATS: [&str; 2] = ["%Y-%m-%d", "%d.%m.%Y"];
+pub const LABEL_SEPARATOR: &str = ";";
+/// Names of the fields which can be extracted with `Task::field`.
+pub const FIELDS: &[&str] = &["id", "text", "created", "completed", "started", "labels", "tags", "priority", "due", "age"];
+pub const MAX_PRIORITY: u8 = 5;
+//const TIME_FORMAT: &str = "%Y-%m-%dT%H:%M:%S";
+
+/// Parses a date or date and time given by the user.
+pub fn parse_datetime(string: &str) -> Option<DateTime<FixedOffset>> {
+    let string = string.trim();
+    if let Ok(time) = DateTime::parse_from_str(string, TIME_FORMAT) {
+        return Some(time);
+    }
+    let naive = INPUT_TIME_FORMATS.iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(string, format).ok())
+        .or_else(|| INPUT_DATE_FORMATS.iter()
+            .find_map(|format| NaiveDate::parse_from_str(string, format).ok())
+            .map(|date| date.and_hms(0, 0, 0)))?;
+    Local.from_local_datetime(&naive).earliest().map(DateTime::from)
+}
+
+/// Parses an age like `90d` or `2w`. A number without unit is in days.
+/// Negative ages are rejected.
+pub fn parse_age(string: &str) -> Option<Duration> {
+    let string = string.trim();
+    let (number, unit) = match string.strip_suffix(|c: char| c.is_ascii_alphabetic()) {
+        Some(number) => (number, &string[number.len()..]),
+        None => (string, "d"),
+    };
+    let number = i64::from(number.parse::<u32>().ok()?);
+    match unit {
+        "d" => Some(Duration::days(number)),
+        "w" => Some(Duration::weeks(number)),
+        _ => None,
+    }
+}
+
+/// Returns whether the file is stored compressed, which is the case for files
+/// ending in `.gz`.
+fn is_compressed(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| extension == "gz")
+}
+
+/// Writes the content of a task file, compressing it if needed.
+fn write_task_file(path: &Path, content: &[u8]) -> Result<(), TaskFileError> {
+    let mut file = fs::File::create(path).map_err(|_| TaskFileError::NotFound)?;
+    let result = match is_compressed(path) {
+        true => {
+            let mut encoder = GzEncoder::new(file, Compression::default());
+            encoder.write_all(content).and_then(|_| encoder.finish()).map(|_| ())
+        }
+        false => file.write_all(content),
+    };
+    result.map_err(|_| TaskFileError::WriteColumn)
+}
+
+/// Reads a task file, returning its format version and the content after the
+/// format marker.
+fn read_task_file(path: &Path) -> Result<(u32, String), TaskFileError> {
+    let mut content = fs::read(path).map_err(|_| TaskFileError::NotFound)?;
+    if is_compressed(path) {
+        let mut decompressed = vec![];
+        GzDecoder::new(content.as_slice()).read_to_end(&mut decompressed)
+            .map_err(|_| TaskFileError::Compression)?;
+        content = decompressed;
+    }
+    split_format_marker(content)
+}
+
+/// Splits the uncompressed content of a task file into its format version and
+/// the content after the format marker.
+fn split_format_marker(content: Vec<u8>) -> Result<(u32, String), TaskFileError> {
+    // Don't lose the whole list because another tool wrote invalid text.
+    let content = String::from_utf8(content).unwrap_or_else(|error| {
+        println!("Warning: task file is not valid UTF-8, invalid characters were replaced");
+        String::from_utf8_lossy(error.as_bytes()).into_owned()
+    });
+    // Files written before the format marker was introduced start with the header.
+    let (version, content) = match content.strip_prefix(FORMAT_MARKER) {
+        Some(rest) => {
+            let (version, rest) = rest.split_once('\n').unwrap_or((rest, ""));
+            (version.trim().parse::<u32>().map_err(|_| TaskFileError::InvalidFormat)?, rest.to_string())
+        }
+        None => (1, content),
+    };
+    if version > FORMAT_VERSION {
+        println!("Warning: task file has format {}, but only format {} is supported. \
+            Some information might be lost.", version, FORMAT_VERSION);
+    }
+    Ok((version, content))
+}
+
+/// Returns whether the record is the header row of a task file. Files in
+/// older formats have fewer columns.
+fn is_header(record: &csv::StringRecord) -> bool {
+    !record.is_empty() && record.len() <= HEADER.len() && record.iter().eq(HEADER[..record.len()].iter().copied())
+}
+
+/// Returns the first day of the week containing the day, with weeks starting on
+/// `week_start`.
+pub fn start_of_week(day: NaiveDate, week_start: Weekday) -> NaiveDate {
+    let offset = (day.weekday().num_days_from_monday() + 7 - week_start.num_days_from_monday()) % 7;
+    day - Duration::days(offset.into())
+}
+
+/// A problem found in a task file by `check_file`.
+pub struct FileProblem {
+    pub line: u64,
+    pub message: String,
+}
+
+impl Display for FileProblem {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+/// Checks every record of a task file without changing it, unlike `load`
+/// which skips or stops at malformed records.
+pub fn check_file(path: &Path, settings: &FileSettings) -> Result<Vec<FileProblem>, TaskFileError> {
+    let (version, content) = read_task_file(path)?;
+    let offset = if version > 1 { 1 } else { 0 };
+    let mut problems = vec![];
+    let mut ids: HashMap<String, u64> = HashMap::new();
+    let mut columns = None;
+    for (num, result) in csv::ReaderBuilder::new()
+            .delimiter(settings.delimiter)
+            .has_headers(false)
+            .flexible(true)
+            .from_reader(content.as_bytes())
+            .records()
+            .enumerate() {
+        let record = match result {
+            Ok(record) => record,
+            Err(error) => {
+                let line = error.position().map_or(0, |position| position.line()) + offset;
+                problems.push(FileProblem{line, message: format!("can't be read: {}", error)});
+                continue;
+            }
+        };
+        let line = record.position().map_or(0, |position| position.line()) + offset;
+        let mut problem = |message: String| problems.push(FileProblem{line, message});
+        match columns {
+            None => columns = Some(record.len()),
+            Some(columns) if columns != record.len() =>
+                problem(format!("has {} columns instead of {}", record.len(), columns)),
+            _ => {}
+        }
+        if num == 0 && is_header(&record) {
+            continue;
+        }
+        if record.len() < 3 {
+            problem("is missing the created or completed column".to_string());
+            continue;
+        }
+        if record[0].trim().is_empty() {
+            problem("has an empty text".to_string());
+        }
+        let created = DateTime::parse_from_str(&record[1], TIME_FORMAT);
+        if created.is_err() {
+            problem(format!("has an invalid creation time '{}'", &record[1]));
+        }
+        match (&record[2], created) {
+            ("", _) => {}
+            (time, created) => match DateTime::parse_from_str(time, TIME_FORMAT) {
+                Ok(completed) if created.is_ok_and(|created| completed < created) =>
+                    problem("was completed before it was created".to_string()),
+                Ok(_) => {}
+                Err(_) => problem(format!("has an invalid completion time '{}'", time)),
+            },
+        }
+        match record.get(4).unwrap_or("") {
+            "" => {}
+            id if id.parse::<u32>().is_err() => problem(format!("has an invalid id '{}'", id)),
+            id => if let Some(first) = ids.insert(id.to_string(), line) {
+                problem(format!("has the same id {} as line {}", id, first));
+            },
+        }
+        match record.get(5).unwrap_or("") {
+            "" => {}
+            priority if priority.parse::<u8>().is_err() =>
+                problem(format!("has an invalid priority '{}'", priority)),
+            _ => {}
+        }
+        match record.get(6).unwrap_or("") {
+            "" => {}
+            history if serde_json::from_str::<Vec<String>>(history).is_err() =>
+                problem("has an invalid history".to_string()),
+            _ => {}
+        }
+        match record.get(7).unwrap_or("") {
+            "" => {}
+            due if DateTime::parse_from_str(due, TIME_FORMAT).is_err() =>
+                problem(format!("has an invalid due date '{}'", due)),
+            _ => {}
+        }
+        match record.get(8).unwrap_or("") {
+            "" => {}
+            started if DateTime::parse_from_str(started, TIME_FORMAT).is_err() =>
+                problem(format!("has an invalid start time '{}'", started)),
+            _ => {}
+        }
+    }
+    Ok(problems)
+}
+
+impl Tasks {
+    /// Creates an empty list.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Loads the tasks from the file and appends them to the list, giving
+    /// tasks without id a new one. Use `reload` to replace the tasks instead.
+    /// In strict mode, any malformed record is an error. Otherwise records
+    /// which can't be read or lack a valid creation time are skipped, and
+    /// invalid values in the other columns are left empty. Returns how many
+    /// records were skipped, which are lost when the list is saved.
+    pub fn load(&mut self, path: &Path, settings: &FileSettings) -> Result<usize, TaskFileError> {
+        let (version, content) = read_task_file(path)?;
+        self.load_content(version, &content, settings, &path.display().to_string())
+    }
+
+    /// Reads an uncompressed task list, like the content of a task file, from
+    /// any source such as standard input. Malformed records are handled like
+    /// in `load`.
+    pub fn from_reader<R: Read>(mut reader: R, settings: &FileSettings) -> Result<Tasks, TaskFileError> {
+        let mut content = vec![];
+        reader.read_to_end(&mut content).map_err(|_| TaskFileError::Read)?;
+        let (version, content) = split_format_marker(content)?;
+        let mut tasks = Tasks::new();
+        tasks.load_content(version, &content, settings, "input")?;
+        Ok(tasks)
+    }
+
+    /// Appends the tasks in the content of a task file in the given format,
+    /// returning how many records were skipped. The source is only used in
+    /// messages.
+    fn load_content(&mut self, version: u32, content: &str, settings: &FileSettings, source: &str)
+            -> Result<usize, TaskFileError> {
+        let strict = settings.strict;
+        // Report line numbers relative to the whole file, including the marker.
+        let offset = if version > 1 { 1 } else { 0 };
+        let mut next_id = self.next_id();
+        let count = self.tasks.len();
+        let mut skipped = 0;
+        for (num, result) in csv::ReaderBuilder::new()
+                .delimiter(settings.delimiter)
+                .has_headers(false)
+                .from_reader(content.as_bytes())
+                .records()
+                .enumerate() {
+            report_progress(settings, "Loading", num);
+            let record = match result {
+                Ok(record) => record,
+                Err(error) if strict => return Err(TaskFileError::InvalidRecord(
+                        error.position().map_or(0, |position| position.line()) + offset)),
+                Err(_) => {
+                    skipped += 1;
+                    continue;
+                }
+            };
+            // Files saved without a header start with a task.
+            if num == 0 && is_header(&record) {
+                continue;
+            }
+            let line = record.position().map_or(0, |position| position.line()) + offset;
+            if record.len() < 3 {
+                match strict {
+                    true => return Err(TaskFileError::MissingColumn(line)),
+                    false => {
+                        skipped += 1;
+                        continue;
+                    }
+                }
+            }
+            // A task can't be restored without its creation time.
+            let created = match DateTime::parse_from_str(&record[1], TIME_FORMAT) {
+                Ok(created) => created,
+                Err(_) if strict => return Err(TaskFileError::ParseColmn(line, "created")),
+                Err(_) => {
+                    skipped += 1;
+                    continue;
+                }
+            };
+            let completed = match &record[2] {
+                "" => None,
+                time => match DateTime::parse_from_str(time, TIME_FORMAT) {
+                    Ok(time) => Some(time),
+                    Err(_) if strict => return Err(TaskFileError::ParseColmn(line, "completed")),
+                    Err(_) => None,
+                },
+            };
+            // Tasks from older files don't have an id yet.
+            let id = match record.get(4).unwrap_or("").parse() {
+                Ok(id) => id,
+                Err(_) if strict && !record.get(4).unwrap_or("").is_empty() =>
+                    return Err(TaskFileError::ParseColmn(line, "id")),
+                Err(_) => {
+                    next_id += 1;
+                    next_id - 1
+                }
+            };
+            let priority = match record.get(5).unwrap_or("") {
+                "" => 0,
+                priority => match priority.parse::<u8>() {
+                    Ok(priority) => priority.min(MAX_PRIORITY),
+                    Err(_) if strict => return Err(TaskFileError::ParseColmn(line, "priority")),
+                    Err(_) => 0,
+                },
+            };
+            self.tasks.push(Task{
+                id,
+                text: record[0].to_string(),
+                created,
+                completed,
+                labels: record.get(3).unwrap_or("").split(LABEL_SEPARATOR)
+                    .filter(|label| !label.is_empty())
+                    .map(|label| label.to_string())
+                    .collect(),
+                priority,
+                due: match record.get(7).unwrap_or("") {
+                    "" => None,
+                    time => match DateTime::parse_from_str(time, TIME_FORMAT) {
+                        Ok(time) => Some(time),
+                        Err(_) if strict => return Err(TaskFileError::ParseColmn(line, "due")),
+                        Err(_) => None,
+                    },
+                },
+                started: match record.get(8).unwrap_or("") {
+                    "" => None,
+                    time => match DateTime::parse_from_str(time, TIME_FORMAT) {
+                        Ok(time) => Some(time),
+                        Err(_) if strict => return Err(TaskFileError::ParseColmn(line, "started")),
+                        Err(_) => None,
+                    },
+                },
+                history: match record.get(6).unwrap_or("") {
+                    "" => vec![],
+                    history => match serde_json::from_str(history) {
+                        Ok(history) => history,
+                        Err(_) if strict => return Err(TaskFileError::ParseColmn(line, "history")),
+                        Err(_) => vec![],
+                    },
+                },
+            })
+        }
+        finish_progress(settings, self.tasks.len());
+        if settings.verbose {
+            eprintln!("Loaded {} tasks in format {} from {}, skipped {} malformed records",
+                self.tasks.len() - count, version, source, skipped);
+        }
+        Ok(skipped)
+    }
+
+    /// Replaces the tasks with the ones in the file, for example after it was
+    /// changed by another program. If the file can't be loaded, the tasks are
+    /// kept as they are. Returns how many records were skipped like `load`.
+    pub fn reload(&mut self, path: &Path, settings: &FileSettings) -> Result<usize, TaskFileError> {
+        let mut tasks = Tasks::new();
+        let skipped = tasks.load(path, settings)?;
+        *self = tasks;
+        Ok(skipped)
+    }
+
+    pub fn save(self, path: &Path, settings: &FileSettings) -> Result<(), TaskFileError> {
+        if settings.verbose {
+            eprintln!("Saving {} tasks to {}", self.tasks.len(), path.display());
+        }
+        // Write the whole list before touching the file.
+        let mut content = vec![];
+        self.to_writer(&mut content, settings)?;
+        write_task_file(path, &content)
+    }
+
+    /// Writes the tasks uncompressed in the format of task files to any
+    /// destination such as standard output.
+    pub fn to_writer<W: Write>(&self, mut writer: W, settings: &FileSettings) -> Result<(), TaskFileError> {
+        // Files without a header are meant for other tools, which don't
+        // expect the format marker either.
+        if settings.header {
+            writeln!(writer, "{}{}", FORMAT_MARKER, FORMAT_VERSION)
+                .map_err(|_| TaskFileError::WriteColumn)?;
+        }
+        let mut writer = csv::WriterBuilder::new()
+            .delimiter(settings.delimiter)
+            .from_writer(writer);
+        if settings.header {
+            writer.write_record(HEADER)
+                .map_err(|_| TaskFileError::WriteColumn)?;
+        }
+        let count = self.tasks.len();
+        for (num, task) in self.tasks.iter().enumerate() {
+            report_progress(settings, "Saving", num);
+            writer.write_record([
+                task.text.clone(),
+                task.created.format(TIME_FORMAT).to_string(),
+                match task.completed {
+                    Some(time) => time.format(TIME_FORMAT).to_string(),
+                    _ => "".to_string(),
+                },
+                task.labels.join(LABEL_SEPARATOR),
+                task.id.to_string(),
+                task.priority.to_string(),
+                match task.history.is_empty() {
+                    true => "".to_string(),
+                    false => serde_json::Value::from(task.history.clone()).to_string(),
+                },
+                task.due.map(|time| time.format(TIME_FORMAT).to_string()).unwrap_or_default(),
+                task.started.map(|time| time.format(TIME_FORMAT).to_string()).unwrap_or_default(),
+            ]).map_err(|_| TaskFileError::WriteColumn)?;
+        }
+        writer.flush().map_err(|_| TaskFileError::WriteColumn)?;
+        finish_progress(settings, count);
+        Ok(())
+    }
+    
+    pub fn print_task(&self, task: usize, view: &View) {
+        if let OutputFormat::Porcelain = view.format {
+            let task = &self.tasks[task];
+            let state = match task.completed {
+                Some(_) => "done",
+                None => "active",
+            };
+            // Keep every task on one line with exactly four columns.
+            let text = task.text.replace(['\t', '\n', '\r'], " ");
+            println!("{}\t{}\t{}\t{}", task.id, state, task.created.format(TIME_FORMAT), text);
+            return;
+        }
+        if view.plain {
+            println!("{}", self.tasks[task].text);
+            return;
+        }
+        let line = self.format_task(task, view);
+        let content = &self.tasks[task];
+        let color = match (view.highlight_due, content.due) {
+            (Some(soon), Some(due)) if content.is_active() => {
+                let left = due.signed_duration_since(Local::now());
+                match left {
+                    _ if left < Duration::zero() => Some(RED),
+                    _ if left < soon => Some(YELLOW),
+                    _ => None,
+                }
+            }
+            _ => None,
+        };
+        match color {
+            Some(color) => println!("{}{}{}", color, line, RESET),
+            None => println!("{}", line),
+        }
+    }
+
+    /// Formats the task as a line like `print_task` does in text output,
+    /// numbered unless turned off in the view.
+    pub fn format_task(&self, task: usize, view: &View) -> String {
+        let content = &self.tasks[task];
+        let text = match view.stale_days {
+            Some(days) if content.is_active()
+                    && content.age(DateTime::from(Local::now())) > Duration::days(days) =>
+                format!("~ {}", content),
+            _ => content.to_string(),
+        };
+        let mut line = match view.numbered {
+            false => match view.truncate {
+                Some(width) => truncate(&text, width),
+                None => text,
+            },
+            true => {
+                // Pad the numbers so the task text lines up in long lists.
+                let digits = self.tasks.len().to_string().len();
+                match view.truncate {
+                    // The number and the space after it take up columns too.
+                    Some(width) => format!("{:>digits$} {}", task + 1, truncate(&text, width.saturating_sub(digits + 1))),
+                    None => format!("{:>digits$} {}", task + 1, text),
+                }
+            }
+        };
+        // Show the times as they are written to the file, after clipping so
+        // they are never cut off.
+        if view.raw_time {
+            line.push_str(&format!("  created {}", content.created.format(TIME_FORMAT)));
+            if let Some(completed) = content.completed {
+                line.push_str(&format!(" completed {}", completed.format(TIME_FORMAT)));
+            }
+        }
+        line
+    }
+
+    /// Prints the tasks in as many columns as fit into the width, filling
+    /// each column from top to bottom like `ls`.
+    fn print_columns(&self, selected: &[usize], view: &View, width: usize) {
+        const GAP: usize = 2;
+        let cells: Vec<String> = selected.iter().map(|task| self.format_task(*task, view)).collect();
+        let cell_width = cells.iter().map(|cell| cell.width()).max().unwrap_or(0) + GAP;
+        let columns = ((width + GAP) / cell_width).max(1);
+        let rows = cells.len().div_ceil(columns);
+        for row in 0..rows {
+            let line: String = (row..cells.len()).step_by(rows.max(1))
+                .map(|cell| format!("{}{}", cells[cell], " ".repeat(cell_width - cells[cell].width())))
+                .collect();
+            println!("{}", line.trim_end());
+        }
+    }
+
+    /// Returns the task as JSON together with its `index`, the number it has
+    /// in listings and commands like `td done`.
+    fn task_json(&self, task: usize) -> serde_json::Value {
+        let mut json = self.tasks[task].to_json();
+        json["index"] = json!(task + 1);
+        json
+    }
+
+    pub fn print_tasks(&self, selected: &[usize], view: &View) {
+        match view.format {
+            OutputFormat::Text => {
+                if let (Some(context), false) = (&view.context, view.plain || view.quiet) {
+                    println!("[{}]", context);
+                }
+                selected.iter().for_each(|t| self.print_task(*t, view))
+            }
+            OutputFormat::Porcelain => selected.iter().for_each(|t| self.print_task(*t, view)),
+            OutputFormat::Json => {
+                let tasks = serde_json::Value::Array(selected.iter().map(|t| self.task_json(*t)).collect());
+                match view.pretty {
+                    true => println!("{:#}", tasks),
+                    false => println!("{}", tasks),
+                }
+            }
+            OutputFormat::JsonLines => {
+                // Write each task as soon as it is serialized so consumers can
+                // start processing before the whole list is printed.
+                let mut stdout = io::stdout().lock();
+                for task in selected {
+                    if writeln!(stdout, "{}", self.task_json(*task))
+                            .and_then(|_| stdout.flush()).is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Prints the tasks under a header for each of their tags, sorted by tag,
+    /// followed by the tasks without tags. Tasks with several tags are
+    /// printed under each of them.
+    pub fn print_grouped_by_tag(&self, selected: &[usize], view: &View) {
+        let mut tags: Vec<&str> = selected.iter().flat_map(|task| self.tasks[*task].tags()).collect();
+        tags.sort_unstable();
+        tags.dedup();
+        if let (Some(context), false) = (&view.context, view.plain || view.quiet) {
+            println!("[{}]", context);
+        }
+        for tag in tags {
+            println!("{}", tag);
+            selected.iter().filter(|task| self.tasks[**task].tags().any(|other| other == tag))
+                .for_each(|task| self.print_task(*task, view));
+        }
+        let mut untagged = selected.iter().filter(|task| self.tasks[**task].tags().next().is_none()).peekable();
+        if untagged.peek().is_some() {
+            println!("(untagged)");
+            untagged.for_each(|task| self.print_task(*task, view));
+        }
+    }
+
+    /// Prints the unfinished tasks.
+    pub fn status(&self, view: &View) {
+        self.status_with(view, DoneHandling::Hide)
+    }
+
+    /// Prints the tasks under a header, including completed ones with
+    /// `DoneHandling::Show`.
+    pub fn status_with(&self, view: &View, done: DoneHandling) {
+        // A header without any tasks looks like something went wrong.
+        if self.tasks.is_empty() && matches!(view.format, OutputFormat::Text) && !view.plain && !view.quiet {
+            println!("No tasks yet — add one with 'td add <text>'");
+            return;
+        }
+        let selected = self.select(&AllSelector{}, done);
+        // Other formats are read by programs, which don't expect a header.
+        if !matches!(view.format, OutputFormat::Text) {
+            self.print_tasks(&selected, view);
+            return;
+        }
+        match (&view.context, view.plain) {
+            (_, true) => {}
+            (Some(context), false) if !view.quiet => println!("[{}] Tasks:", context),
+            (_, false) => println!("Tasks:"),
+        }
+        match (view.columns, &view.format, view.plain) {
+            (Some(width), OutputFormat::Text, false) => self.print_columns(&selected, view, width),
+            _ => selected.iter().for_each(|task| self.print_task(*task, view)),
+        }
+    }
+
+    /// Returns the positions of the tasks matching the selector. Use this
+    /// when the tasks are going to be modified, otherwise `select_tasks` is
+    /// more convenient.
+    pub fn select(&self, selector: &(impl TaskSelector + ?Sized), done: DoneHandling) -> Vec<usize> {
+        let mut selected: Vec<usize> = vec![];
+        for (task_num, task) in self.tasks.iter().enumerate() {
+            if match done {
+                        DoneHandling::Show => true,
+                        DoneHandling::Hide => task.is_active(),
+                        DoneHandling::Only => task.is_completed(),
+                    } && selector.matches(self, task_num) {
+                selected.push(task_num);
+            }
+        }
+        selected
+    }
+
+    /// Returns the tasks matching the selector, in list order.
+    pub fn select_tasks(&self, selector: &(impl TaskSelector + ?Sized), done: DoneHandling) -> Vec<&Task> {
+        self.select(selector, done).into_iter().map(|task| &self.tasks[task]).collect()
+    }
+
+    pub fn next_id(&self) -> u32 {
+        self.tasks.iter().map(|task| task.id).max().unwrap_or(0) + 1
+    }
+
+    /// Returns the current position of the task with the given id.
+    pub fn find_id(&self, id: u32) -> Option<usize> {
+        self.tasks.iter().position(|task| task.id == id)
+    }
+
+    /// Adds the task at the position, giving it a new id. Control characters
+    /// in its text are removed.
+    pub fn create(&mut self, mut task: Task, position: usize) {
+        task.id = self.next_id();
+        task.text = strip_control(&task.text);
+        println!("Created new task: {}", task);
+        self.tasks.insert(position, task);
+        self.dirty = true;
+    }
+
+    /// Appends the tasks of another list, returning how many were added.
+    /// With `dedupe`, tasks with the same text and creation time as an
+    /// existing task are skipped. Added tasks keep their id unless it is
+    /// already used by another task, in which case they get a new one.
+    pub fn merge(&mut self, other: Tasks, dedupe: bool) -> usize {
+        let mut added = 0;
+        for mut task in other.tasks {
+            if dedupe && self.tasks.iter().any(|existing|
+                    existing.text == task.text && existing.created == task.created) {
+                continue;
+            }
+            if task.id == 0 || self.find_id(task.id).is_some() {
+                task.id = self.next_id();
+            }
+            self.tasks.push(task);
+            added += 1;
+            self.dirty = true;
+        }
+        added
+    }
+
+    /// Moves the tasks to the top of the list, keeping their order.
+    pub fn bump(&mut self, selected: &[usize]) {
+        let (mut bumped, rest): (Vec<_>, Vec<_>) = std::mem::take(&mut self.tasks).into_iter()
+            .enumerate()
+            .partition(|(num, _)| selected.contains(num));
+        bumped.extend(rest);
+        self.tasks = bumped.into_iter().map(|(_, task)| task).collect();
+        self.dirty |= !selected.is_empty();
+    }
+
+    /// Changes the text of the task. With `keep_history`, the previous text is
+    /// added to the history of the task.
+    pub fn reword(&mut self, task: usize, text: String, keep_history: bool) -> Result<(), TaskError> {
+        let task = self.tasks.get_mut(task).ok_or(TaskError::NotFound)?;
+        let text = strip_control(&text);
+        println!("Reworded {} to {}", task.text, text);
+        let previous = std::mem::replace(&mut task.text, text);
+        if keep_history {
+            task.history.push(previous);
+        }
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Sets the creation time of the task to the given time, keeping
+    /// everything else.
+    pub fn touch(&mut self, task: usize, time: DateTime<FixedOffset>) -> Result<(), TaskError> {
+        let task = self.tasks.get_mut(task).ok_or(TaskError::NotFound)?;
+        if task.completed.is_some_and(|completed| completed < time) {
+            return Err(TaskError::CompletedBeforeCreated);
+        }
+        task.created = time;
+        println!("Touched {}", task);
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Returns the number of unfinished and completed tasks.
+    pub fn counts(&self) -> (usize, usize) {
+        let completed = self.tasks.iter().filter(|task| task.is_completed()).count();
+        (self.tasks.len() - completed, completed)
+    }
+
+    /// Moves the task to the top of the list and marks it as started at the
+    /// time. Other tasks which were started but aren't finished yet are
+    /// mentioned.
+    pub fn work_on(&mut self, task: usize, time: DateTime<FixedOffset>) -> Result<(),TaskError> {
+        if let Some(content) = self.tasks.get(task) {
+            println!("Working on {}!", content);
+            for (_, previous) in self.tasks.iter().enumerate()
+                    .filter(|(other, previous)| *other != task && previous.is_active() && previous.started.is_some()) {
+                println!("(still in progress: \"{}\")", previous.text);
+            }
+            let mut working = self.tasks.remove(task);
+            if working.started.is_none() {
+                working.started = Some(time);
+                self.dirty = true;
+            }
+            self.tasks.insert(0, working);
+            self.dirty |= task != 0;
+            Ok(())
+        } else {
+            Err(TaskError::NotFound)
+        }
+    }
+
+    pub fn label(&mut self, task: usize, label: &str) -> Result<(),TaskError> {
+        let content = self.tasks.get_mut(task).ok_or(TaskError::NotFound)?;
+        if !content.labels.iter().any(|l| l == label) {
+            content.labels.push(label.to_string());
+            self.dirty = true;
+        }
+        println!("Labeled {}", content);
+        Ok(())
+    }
+
+    pub fn unlabel(&mut self, task: usize, label: &str) -> Result<(),TaskError> {
+        let content = self.tasks.get_mut(task).ok_or(TaskError::NotFound)?;
+        let count = content.labels.len();
+        content.labels.retain(|l| l != label);
+        self.dirty |= content.labels.len() != count;
+        println!("Unlabeled {}", content);
+        Ok(())
+    }
+
+    /// Returns all labels in use, sorted and without duplicates.
+    pub fn labels(&self) -> Vec<&str> {
+        let mut labels: Vec<&str> = self.tasks.iter()
+            .flat_map(|task| task.labels.iter().map(|label| label.as_str()))
+            .collect();
+        labels.sort_unstable();
+        labels.dedup();
+        labels
+    }
+
+    /// Returns all tags like `@work` used in the texts of the tasks, sorted
+    /// and without duplicates.
+    pub fn tags(&self) -> Vec<&str> {
+        let mut tags: Vec<&str> = self.tasks.iter().flat_map(Task::tags).collect();
+        tags.sort_unstable();
+        tags.dedup();
+        tags
+    }
+
+    /// Returns the unfinished tasks created more than `days` days before `now`,
+    /// oldest first.
+    pub fn stale(&self, now: DateTime<FixedOffset>, days: i64) -> Vec<usize> {
+        let mut stale: Vec<usize> = (0..self.tasks.len())
+            .filter(|task| self.tasks[*task].is_active()
+                && self.tasks[*task].age(now) > Duration::days(days))
+            .collect();
+        stale.sort_by_key(|task| self.tasks[*task].created);
+        stale
+    }
+
+    /// Counts the completed tasks by the week they were completed in like
+    /// `completions_per_day`, using the first day of each week.
+    pub fn completions_per_week(&self, week_start: Weekday) -> Vec<(NaiveDate, usize)> {
+        let mut weeks: Vec<(NaiveDate, usize)> = vec![];
+        for (day, count) in self.completions_per_day() {
+            let week = start_of_week(day, week_start);
+            match weeks.last_mut() {
+                Some((last, total)) if *last == week => *total += count,
+                _ => weeks.push((week, count)),
+            }
+        }
+        weeks
+    }
+
+    /// Counts the completed tasks by the day they were completed on, from the
+    /// first to the last day with a completion. Days without completions are
+    /// included with a count of zero.
+    pub fn completions_per_day(&self) -> Vec<(NaiveDate, usize)> {
+        let mut counts: HashMap<NaiveDate, usize> = HashMap::new();
+        for completed in self.tasks.iter().filter_map(|task| task.completed) {
+            *counts.entry(completed.naive_local().date()).or_default() += 1;
+        }
+        let (Some(first), Some(last)) = (counts.keys().min(), counts.keys().max()) else {
+            return vec![];
+        };
+        first.iter_days().take_while(|day| day <= last)
+            .map(|day| (day, counts.get(&day).copied().unwrap_or(0)))
+            .collect()
+    }
+
+    /// Swaps the task with the previous unfinished task. Nothing happens if it
+    /// already is the first one.
+    pub fn move_up(&mut self, task: usize) -> Result<(), TaskError> {
+        if task >= self.tasks.len() {
+            return Err(TaskError::NotFound);
+        }
+        if let Some(previous) = (0..task).rev().find(|t| self.tasks[*t].is_active()) {
+            self.tasks.swap(previous, task);
+            self.dirty = true;
+        }
+        Ok(())
+    }
+
+    /// Swaps the task with the next unfinished task. Nothing happens if it
+    /// already is the last one.
+    pub fn move_down(&mut self, task: usize) -> Result<(), TaskError> {
+        if task >= self.tasks.len() {
+            return Err(TaskError::NotFound);
+        }
+        if let Some(next) = (task + 1..self.tasks.len()).find(|t| self.tasks[*t].is_active()) {
+            self.tasks.swap(task, next);
+            self.dirty = true;
+        }
+        Ok(())
+    }
+
+    /// Changes the priority of the task by `delta`, staying between zero and
+    /// `MAX_PRIORITY`. Returns the new priority.
+    pub fn nudge_priority(&mut self, task: usize, delta: i8) -> Result<u8, TaskError> {
+        let task = self.tasks.get_mut(task).ok_or(TaskError::NotFound)?;
+        let priority = task.priority.saturating_add_signed(delta).min(MAX_PRIORITY);
+        self.dirty |= priority != task.priority;
+        task.priority = priority;
+        Ok(priority)
+    }
+
+    /// Writes the tasks in the format used by `td edit --editor`.
+    pub fn to_editable(&self) -> String {
+        let mut content = EDIT_HELP.to_string();
+        for task in &self.tasks {
+            let mark = match task.completed {
+                Some(_) => "x",
+                None => " ",
+            };
+            content.push_str(&format!("{} [{}] {}\n", task.id, mark, escape_line(&task.text)));
+        }
+        content
+    }
+
+    /// Writes the given fields of all tasks as CSV with a header row, for use
+    /// in spreadsheets. See `FIELDS` for the names.
+    pub fn export_csv(&self, fields: &[&str], writer: impl Write) -> csv::Result<()> {
+        let mut writer = csv::Writer::from_writer(writer);
+        writer.write_record(fields)?;
+        for task in &self.tasks {
+            writer.write_record(fields.iter().map(|field| task.field(field).unwrap_or_default()))?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Writes the unfinished tasks with a due date as all-day iCalendar events
+    /// on that date.
+    pub fn to_ics(&self) -> String {
+        // Text values have to escape these characters, lines end with CRLF.
+        let escape = |text: &str| text.replace('\\', "\\\\").replace(';', "\\;")
+            .replace(',', "\\,").replace('\n', "\\n");
+        let stamp = chrono::Utc::now().format("%Y%m%dT%H%M%SZ");
+        let mut content = "BEGIN:VCALENDAR\r\nVERSION:2.0\r\nPRODID:-//td//td//EN\r\n".to_string();
+        for task in self.tasks.iter().filter(|task| task.is_active()) {
+            let Some(due) = task.due else {
+                continue;
+            };
+            content.push_str(&format!("BEGIN:VEVENT\r\nUID:td-{}-{}\r\nDTSTAMP:{}\r\n\
+                DTSTART;VALUE=DATE:{}\r\nSUMMARY:{}\r\nEND:VEVENT\r\n",
+                task.id, task.created.timestamp(), stamp, due.format("%Y%m%d"), escape(&task.text)));
+        }
+        content.push_str("END:VCALENDAR\r\n");
+        content
+    }
+
+    /// Returns all tasks wrapped in an object recording the version of the
+    /// JSON format and when it was generated.
+    pub fn to_json_envelope(&self) -> serde_json::Value {
+        json!({
+            "version": JSON_VERSION,
+            "generated": Local::now().to_rfc3339(),
+            "tasks": (0..self.tasks.len()).map(|task| self.task_json(task)).collect::<Vec<_>>(),
+        })
+    }
+
+    /// Writes the tasks as a self-contained HTML page for sharing. If any task
+    /// has tags, the tasks are grouped under a heading for each tag like in
+    /// the grouped Markdown export.
+    pub fn to_html(&self) -> String {
+        let escape = |text: &str| text.replace('&', "&amp;").replace('<', "&lt;")
+            .replace('>', "&gt;").replace('"', "&quot;").replace('\'', "&#39;");
+        let list = |tasks: Vec<&Task>| {
+            let mut content = "<ul>\n".to_string();
+            for task in tasks {
+                let (class, checked) = match task.completed {
+                    Some(_) => (" class=\"done\"", " checked"),
+                    None => ("", ""),
+                };
+                content.push_str(&format!("<li{}><label><input type=\"checkbox\" disabled{}> {}</label></li>\n",
+                    class, checked, escape(&task.text)));
+            }
+            content.push_str("</ul>\n");
+            content
+        };
+        let mut content = "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>Tasks</title>\n\
+            <style>\nbody { font-family: sans-serif; max-width: 40em; margin: 2em auto; }\n\
+            ul { list-style: none; padding: 0; }\nli { margin: 0.3em 0; }\n\
+            .done { color: #888; text-decoration: line-through; }\n</style>\n</head>\n<body>\n\
+            <h1>Tasks</h1>\n".to_string();
+        let tags = self.tags();
+        if tags.is_empty() {
+            content.push_str(&list(self.tasks.iter().collect()));
+        } else {
+            for tag in tags {
+                content.push_str(&format!("<h2>{}</h2>\n", escape(tag)));
+                content.push_str(&list(self.tasks.iter().filter(|task| task.tags().any(|other| other == tag)).collect()));
+            }
+            let untagged: Vec<&Task> = self.tasks.iter().filter(|task| task.tags().next().is_none()).collect();
+            if !untagged.is_empty() {
+                content.push_str("<h2>Other</h2>\n");
+                content.push_str(&list(untagged));
+            }
+        }
+        content.push_str("</body>\n</html>\n");
+        content
+    }
+
+    /// Sets a field of the task, parsing the value according to the field.
+    /// Times can be removed from optional fields using "none".
+    pub fn set(&mut self, task: usize, field: &str, value: &str) -> Result<(), SetError> {
+        let time = |value: &str| parse_datetime(value).ok_or(SetError::InvalidValue("a date"));
+        let optional_time = |value: &str| match value {
+            "none" => Ok(None),
+            value => parse_datetime(value).map(Some).ok_or(SetError::InvalidValue("a date or none")),
+        };
+        let task = self.tasks.get_mut(task).ok_or(SetError::Task(TaskError::NotFound))?;
+        match field {
+            "text" if value.trim().is_empty() => return Err(SetError::InvalidValue("a text")),
+            "text" => task.text = strip_control(value),
+            "created" => {
+                let created = time(value)?;
+                if task.completed.is_some_and(|completed| completed < created) {
+                    return Err(SetError::Task(TaskError::CompletedBeforeCreated));
+                }
+                task.created = created;
+            }
+            "completed" => {
+                let completed = optional_time(value)?;
+                if completed.is_some_and(|completed| completed < task.created) {
+                    return Err(SetError::Task(TaskError::CompletedBeforeCreated));
+                }
+                task.completed = completed;
+            }
+            "priority" => task.priority = value.parse().ok().filter(|priority| *priority <= MAX_PRIORITY)
+                .ok_or(SetError::InvalidValue("a priority from 0 to 5"))?,
+            "due" => task.due = optional_time(value)?,
+            _ => return Err(SetError::UnknownField),
+        }
+        println!("Set {} of {}", field, task);
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Sets or, with `None`, removes the due date of the task.
+    pub fn set_due(&mut self, task: usize, due: Option<DateTime<FixedOffset>>) -> Result<(), TaskError> {
+        let task = self.tasks.get_mut(task).ok_or(TaskError::NotFound)?;
+        self.dirty |= task.due != due;
+        task.due = due;
+        match due {
+            Some(_) => println!("{}", task),
+            None => println!("Removed due date of {}", task),
+        }
+        Ok(())
+    }
+
+    /// Writes the tasks as a GitHub-style task list. With `grouped`, the tasks
+    /// are put under a heading for each of their tags, followed by the tasks
+    /// without tags.
+    pub fn to_markdown(&self, grouped: bool) -> String {
+        let item = |task: &Task| {
+            let mark = match task.completed {
+                Some(_) => "x",
+                None => " ",
+            };
+            format!("- [{}] {}\n", mark, task.text)
+        };
+        if !grouped {
+            return self.tasks.iter().map(item).collect();
+        }
+        let mut content = String::new();
+        for tag in self.tags() {
+            content.push_str(&format!("## {}\n\n", tag));
+            self.tasks.iter()
+                .filter(|task| task.tags().any(|other| other == tag))
+                .for_each(|task| content.push_str(&item(task)));
+            content.push('\n');
+        }
+        let untagged: String = self.tasks.iter().filter(|task| task.tags().next().is_none()).map(item).collect();
+        if !untagged.is_empty() && !content.is_empty() {
+            content.push_str("## Other\n\n");
+        }
+        content.push_str(&untagged);
+        content
+    }
+
+    /// Replaces the tasks with an edited list created by `to_editable`. Tasks
+    /// missing in the edited list are deleted. If the list is invalid,
+    /// nothing is changed. With `keep_history`, changed texts are added to the
+    /// history of the tasks.
+    pub fn apply_edit(&mut self, content: &str, keep_history: bool) -> Result<(), EditError> {
+        let ids: HashSet<u32> = self.tasks.iter().map(|task| task.id).collect();
+        let mut edited: Vec<(u32, bool, String)> = vec![];
+        for (line, text) in content.lines().enumerate().map(|(num, text)| (num + 1, text)) {
+            if text.trim().is_empty() || text.starts_with('#') {
+                continue;
+            }
+            let (id, rest) = text.split_once(' ').ok_or(EditError::InvalidLine(line))?;
+            let id: u32 = id.parse().map_err(|_| EditError::InvalidLine(line))?;
+            let (done, text) = match rest.get(..3) {
+                Some("[ ]") => (false, &rest[3..]),
+                Some("[x]" | "[X]") => (true, &rest[3..]),
+                _ => return Err(EditError::InvalidLine(line)),
+            };
+            if !ids.contains(&id) {
+                return Err(EditError::UnknownId(line));
+            }
+            if edited.iter().any(|(other, ..)| *other == id) {
+                return Err(EditError::DuplicateId(line));
+            }
+            edited.push((id, done, strip_control(&unescape_line(text.strip_prefix(' ').unwrap_or(text)))));
+        }
+        let now = DateTime::from(Local::now());
+        let mut old: HashMap<u32, Task> = self.tasks.drain(..).map(|task| (task.id, task)).collect();
+        for (id, done, text) in edited {
+            if let Some(mut task) = old.remove(&id) {
+                if keep_history && task.text != text {
+                    task.history.push(std::mem::take(&mut task.text));
+                }
+                task.text = text;
+                match (done, task.completed) {
+                    (true, None) => task.completed = Some(now),
+                    (false, Some(_)) => task.completed = None,
+                    _ => {}
+                }
+                self.tasks.push(task);
+            }
+        }
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Compares the tasks to an older version of the list, matching tasks by id.
+    pub fn diff<'a>(&'a self, old: &'a Tasks) -> Vec<Change<'a>> {
+        let mut changes = vec![];
+        for task in &self.tasks {
+            match old.find_id(task.id).map(|position| &old.tasks[position]) {
+                Some(previous) if previous.text != task.text
+                        || previous.completed != task.completed
+                        || previous.labels != task.labels =>
+                    changes.push(Change::Changed(previous, task)),
+                Some(_) => {}
+                None => changes.push(Change::Added(task)),
+            }
+        }
+        for task in &old.tasks {
+            if self.find_id(task.id).is_none() {
+                changes.push(Change::Removed(task));
+            }
+        }
+        changes
+    }
+
+    /// Marks the task as completed, leaving it at its position so the
+    /// positions of other tasks don't change.
+    pub fn complete(&mut self, num: usize, time: DateTime<FixedOffset>) -> Result<(),TaskError> {
+        if let Some(task) = self.tasks.get_mut(num) {
+            if time < task.created {
+                return Err(TaskError::CompletedBeforeCreated);
+            }
+            task.completed = Some(time);
+            self.dirty = true;
+            Ok(())
+        } else {
+            Err(TaskError::NotFound)
+        }
+    }
+
+    /// Completes all unfinished tasks matching the selector, returning how
+    /// many were completed. Tasks created after the time are skipped.
+    pub fn complete_by_selector(&mut self, selector: &(impl TaskSelector + ?Sized), time: DateTime<FixedOffset>) -> usize {
+        self.select(selector, DoneHandling::Hide).into_iter()
+            .filter(|task| self.complete(*task, time).is_ok())
+            .count()
+    }
+
+    /// Marks all completed tasks matching the selector as unfinished again,
+    /// returning how many were reopened.
+    pub fn uncomplete_by_selector(&mut self, selector: &(impl TaskSelector + ?Sized)) -> usize {
+        let selected: Vec<usize> = self.select(selector, DoneHandling::Show).into_iter()
+            .filter(|task| self.tasks[*task].is_completed())
+            .collect();
+        for task in &selected {
+            self.tasks[*task].completed = None;
+        }
+        self.dirty |= !selected.is_empty();
+        selected.len()
+    }
+
+    /// Marks the most recently completed tasks as unfinished again. All tasks
+    /// completed at that time are reopened, as `td done` completes all
+    /// selected tasks at once. They stay where they are in the list.
+    pub fn undo_last_completion(&mut self) -> Result<(), TaskError> {
+        let last = self.tasks.iter().filter_map(|task| task.completed).max().ok_or(TaskError::NotFound)?;
+        for task in self.tasks.iter_mut().filter(|task| task.completed == Some(last)) {
+            task.completed = None;
+            println!("Reopened {}", task);
+        }
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Removes unfinished tasks with the same text as an older unfinished
+    /// task, returning how many were removed. Whitespace and case are ignored
+    /// when comparing. Completed tasks are kept, as doing something twice is
+    /// valid history.
+    pub fn dedupe(&mut self) -> usize {
+        let normalize = |text: &str| text.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase();
+        let mut oldest: HashMap<String, usize> = HashMap::new();
+        for (num, task) in self.tasks.iter().enumerate().filter(|(_, task)| task.is_active()) {
+            let kept = oldest.entry(normalize(&task.text)).or_insert(num);
+            if task.created < self.tasks[*kept].created {
+                *kept = num;
+            }
+        }
+        let kept: HashSet<usize> = oldest.into_values().collect();
+        let count = self.tasks.len();
+        let mut num = 0;
+        self.tasks.retain(|task| {
+            num += 1;
+            task.is_completed() || kept.contains(&(num - 1))
+        });
+        self.dirty |= self.tasks.len() != count;
+        count - self.tasks.len()
+    }
+
+    /// Removes the completed tasks from the list and returns them.
+    pub fn take_completed(&mut self) -> Tasks {
+        self.take_completed_if(|_| true)
+    }
+
+    /// Removes the completed tasks for which the predicate returns true from
+    /// the list and returns them.
+    pub fn take_completed_if(&mut self, predicate: impl Fn(&Task) -> bool) -> Tasks {
+        let (completed, active) = std::mem::take(&mut self.tasks).into_iter()
+            .partition(|task| task.is_completed() && predicate(task));
+        self.tasks = active;
+        self.dirty |= !completed.is_empty();
+        Tasks{tasks: completed, dirty: false}
+    }
+
+    /// Removes tasks completed before the given time, returning how many were
+    /// removed.
+    pub fn prune(&mut self, before: DateTime<FixedOffset>) -> usize {
+        let count = self.tasks.len();
+        self.tasks.retain(|task| task.completed.is_none_or(|completed| completed >= before));
+        self.dirty |= self.tasks.len() != count;
+        count - self.tasks.len()
+    }
+
+    /// Sorts the tasks, keeping the order of tasks that are equal.
+    pub fn sort(&mut self, key: SortKey) {
+        match key {
+            SortKey::Created => self.tasks.sort_by_key(|task| task.created),
+            SortKey::Text => self.tasks.sort_by_key(|task| task.text.to_lowercase()),
+            SortKey::Priority => self.tasks.sort_by_key(|task| std::cmp::Reverse(task.priority)),
+            SortKey::Id => self.tasks.sort_by_key(|task| task.id),
+            SortKey::Due => self.tasks.sort_by_key(|task| (task.due.is_none(), task.due)),
+        }
+    }
+
+    /// Puts the tasks back into the order of the ids, undoing `sort`. Tasks
+    /// with other ids were added in the meantime, and are put at the end if
+    /// they come after all known tasks, or at the start otherwise.
+    pub fn restore_order(&mut self, ids: &[u32]) {
+        let positions: HashMap<u32, usize> = ids.iter().enumerate().map(|(num, id)| (*id, num)).collect();
+        let last_known = self.tasks.iter().rposition(|task| positions.contains_key(&task.id));
+        let mut tasks: Vec<(usize, Task)> = std::mem::take(&mut self.tasks).into_iter().enumerate().collect();
+        tasks.sort_by_key(|(num, task)| match positions.get(&task.id) {
+            Some(position) => (1, *position),
+            None if last_known.is_some_and(|last| *num > last) => (2, 0),
+            None => (0, 0),
+        });
+        self.tasks = tasks.into_iter().map(|(_, task)| task).collect();
+    }
+
+    /// Moves the completed tasks to the end of the list, keeping their order.
+    pub fn sink_completed(&mut self) {
+        self.dirty |= !self.tasks.is_sorted_by_key(|task| task.is_completed());
+        self.tasks.sort_by_key(|task| task.is_completed());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parses a fixed time, so tests don't depend on the clock.
+    fn time(string: &str) -> DateTime<FixedOffset> {
+        DateTime::parse_from_rfc3339(string).unwrap()
+    }
+
+    /// Creates a list with the tasks numbered from 1, where task N has id 40 + N.
+    fn list(texts: &[&str]) -> Tasks {
+        texts.iter().enumerate()
+            .map(|(index, text)| Task{id: 41 + index as u32, ..Task::new(text.to_string(), time("2024-01-01T00:00:00+00:00"))})
+            .collect()
+    }
+
+    fn validate(tasks: &Tasks, string: &str) -> Result<(), SelectorError> {
+        selector_from_string(string, EmptyBehaviour::SelectAll)?.validate(tasks)
+    }
+
+    /// Returns the settings of a numbered listing like `td show`.
+    fn view() -> View {
+        View{format: OutputFormat::Text, truncate: None, plain: false, numbered: true, quiet: true, raw_time: false,
+            highlight_due: None, stale_days: None, context: None, pretty: false, columns: None}
+    }
+
+    fn settings(delimiter: u8, header: bool) -> FileSettings {
+        FileSettings{strict: true, delimiter, header, progress: false, verbose: false}
+    }
+
+    /// Writes the tasks and reads them back in.
+    fn round_trip(tasks: &Tasks, settings: &FileSettings) -> Tasks {
+        let mut content = vec![];
+        tasks.to_writer(&mut content, settings).unwrap();
+        Tasks::from_reader(content.as_slice(), settings).unwrap()
+    }
+
+    fn texts(tasks: &Tasks) -> Vec<&str> {
+        tasks.tasks.iter().map(|task| task.text.as_str()).collect()
+    }
+
+    /// Creates a list like `list`, with the due dates given for each task.
+    fn due_list(tasks: &[(&str, Option<&str>)]) -> Tasks {
+        let mut list = list(&tasks.iter().map(|(text, _)| *text).collect::<Vec<_>>());
+        for (task, (_, due)) in list.tasks.iter_mut().zip(tasks) {
+            task.due = due.map(time);
+        }
+        list
+    }
+
+    /// Returns a path in the temporary folder which is unique to the test run.
+    fn temp_file(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("td-test-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn selectors_report_missing_tasks() {
+        let tasks = list(&["a", "b", "c"]);
+        assert!(validate(&tasks, "#42").is_ok());
+        assert!(matches!(validate(&tasks, "#999"), Err(SelectorError::UnknownId(999))));
+        assert!(validate(&tasks, "3").is_ok());
+        assert!(matches!(validate(&tasks, "300"), Err(SelectorError::OutOfRange(300))));
+        assert!(matches!(validate(&tasks, "0"), Err(SelectorError::OutOfRange(0))));
+        assert!(matches!(validate(&tasks, "2,0"), Err(SelectorError::OutOfRange(0))));
+        assert!(matches!(validate(&tasks, "0-2"), Err(SelectorError::OutOfRange(0))));
+    }
+
+    #[test]
+    fn wide_characters_are_matched_and_clipped_by_width() {
+        let tasks = list(&["日本語のタスクを書く", "plain"]);
+        let selector = selector_from_string("日本.*タスク", EmptyBehaviour::SelectAll).unwrap();
+        assert_eq!(tasks.select(&*selector, DoneHandling::Show), vec![0]);
+        let view = View{truncate: Some(10), ..view()};
+        let line = tasks.format_task(0, &view);
+        assert!(line.starts_with("1 日本"));
+        assert!(line.ends_with('…'));
+        assert!(line.width() <= 10);
+        assert_eq!(tasks.format_task(1, &view), "2 plain");
+    }
+
+    #[test]
+    fn tab_separated_files_keep_commas_and_tabs() {
+        let tasks = list(&["call Anna, Bob", "a\ttab"]);
+        let settings = settings(b'\t', true);
+        let mut content = vec![];
+        tasks.to_writer(&mut content, &settings).unwrap();
+        assert!(String::from_utf8(content).unwrap().lines().nth(2).unwrap().starts_with("call Anna, Bob\t"));
+        assert_eq!(texts(&round_trip(&tasks, &settings)), vec!["call Anna, Bob", "a\ttab"]);
+    }
+
+    #[test]
+    fn files_are_read_with_and_without_header() {
+        let settings = settings(b',', true);
+        let headered = "text,created,completed\nfirst,2024-01-01T00:00:00+00:00,\n";
+        let headerless = "first,2024-01-01T00:00:00+00:00,\nsecond,2024-01-02T00:00:00+00:00,\n";
+        assert_eq!(texts(&Tasks::from_reader(headered.as_bytes(), &settings).unwrap()), vec!["first"]);
+        assert_eq!(texts(&Tasks::from_reader(headerless.as_bytes(), &settings).unwrap()), vec!["first", "second"]);
+        let tasks = list(&["first", "second"]);
+        let mut content = vec![];
+        tasks.to_writer(&mut content, &FileSettings{header: false, ..settings}).unwrap();
+        assert!(content.starts_with(b"first,"));
+        assert_eq!(texts(&Tasks::from_reader(content.as_slice(), &settings).unwrap()), vec!["first", "second"]);
+    }
+
+    #[test]
+    fn invalid_utf8_is_replaced_instead_of_failing() {
+        let content = b"caf\xe9 order,2024-01-01T00:00:00+00:00,\nnext,2024-01-01T00:00:00+00:00,\n";
+        let tasks = Tasks::from_reader(content.as_slice(), &settings(b',', true)).unwrap();
+        assert_eq!(texts(&tasks), vec!["caf\u{FFFD} order", "next"]);
+    }
+
+    #[test]
+    fn merging_skips_duplicates_and_reassigns_used_ids() {
+        let mut all = list(&["a", "b"]);
+        assert_eq!(all.merge(list(&["b", "c"]), false), 2);
+        assert_eq!(texts(&all), vec!["a", "b", "b", "c"]);
+        let mut tasks = list(&["a", "b"]);
+        assert_eq!(tasks.merge(list(&["b", "c"]), true), 1);
+        assert_eq!(texts(&tasks), vec!["a", "b", "c"]);
+        // "c" had id 42, which "b" already uses.
+        let ids: Vec<u32> = tasks.tasks.iter().map(|task| task.id).collect();
+        assert_eq!(ids, vec![41, 42, 43]);
+    }
+
+    #[test]
+    fn line_breaks_in_tasks_survive_saving() {
+        let tasks = list(&["first line\nsecond line", "after"]);
+        let loaded = round_trip(&tasks, &settings(b',', true));
+        assert_eq!(texts(&loaded), texts(&tasks));
+        assert_eq!(loaded.tasks[0].created, tasks.tasks[0].created);
+        // The editable list has one task per line.
+        let text = "a\\nb\nc";
+        assert!(!escape_line(text).contains('\n'));
+        assert_eq!(unescape_line(&escape_line(text)), text);
+    }
+
+    #[test]
+    fn escape_sequences_are_removed_from_texts() {
+        let mut tasks = Tasks::new();
+        tasks.create(Task::new("\x1b[2Jred\x1b[31m\ttext".to_string(), time("2024-01-01T00:00:00+00:00")), 0);
+        assert_eq!(tasks.tasks[0].text, "[2Jred[31m\ttext");
+        tasks.reword(0, "new\x07\nline\x1b".to_string(), false).unwrap();
+        assert_eq!(tasks.tasks[0].text, "new\nline");
+    }
+
+    #[test]
+    fn age_is_measured_from_creation() {
+        let mut task = Task::new("task".to_string(), time("2024-01-01T12:00:00+00:00"));
+        assert_eq!(task.age(time("2024-01-11T12:00:00+00:00")), Duration::days(10));
+        // Offsets don't change the age.
+        assert_eq!(task.age(time("2024-01-02T14:30:00+02:00")), Duration::minutes(24 * 60 + 30));
+        assert!(task.is_active() && !task.is_completed());
+        task.completed = Some(time("2024-01-05T00:00:00+00:00"));
+        assert!(task.is_completed() && !task.is_active());
+    }
+
+    #[test]
+    fn lists_of_numbers_and_ranges_select_each_of_them() {
+        let tasks = list(&["a", "b", "c", "d", "e", "f", "g", "h"]);
+        let selector = selector_from_string("1,3,5-7", EmptyBehaviour::SelectAll).unwrap();
+        assert_eq!(tasks.select(&*selector, DoneHandling::Show), vec![0, 2, 4, 5, 6]);
+        let selector = selector_from_string("#42, 8", EmptyBehaviour::SelectAll).unwrap();
+        assert_eq!(tasks.select(&*selector, DoneHandling::Show), vec![1, 7]);
+    }
+
+    #[test]
+    fn sorting_by_due_date_puts_undated_tasks_last() {
+        let mut tasks = due_list(&[("undated a", None), ("later", Some("2024-03-01T00:00:00+00:00")),
+            ("undated b", None), ("sooner", Some("2024-02-01T00:00:00+00:00")), ("undated c", None)]);
+        tasks.sort(SortKey::Due);
+        assert_eq!(texts(&tasks), vec!["sooner", "later", "undated a", "undated b", "undated c"]);
+    }
+
+    #[test]
+    fn json_index_matches_the_listed_number() {
+        let mut tasks = due_list(&[("a", None), ("b", Some("2024-03-01T00:00:00+00:00")), ("c", None),
+            ("d", Some("2024-02-01T00:00:00+00:00")), ("e", None), ("f", None), ("g", None), ("h", None),
+            ("i", None), ("j", Some("2024-01-01T00:00:00+00:00"))]);
+        tasks.tasks[2].completed = Some(time("2024-01-02T00:00:00+00:00"));
+        let check = |tasks: &Tasks| {
+            for task in tasks.select(&AllSelector{}, DoneHandling::Hide) {
+                let line = tasks.format_task(task, &view());
+                let (number, text) = line.trim_start().split_once(' ').unwrap();
+                let json = tasks.task_json(task);
+                assert_eq!(json["index"].to_string(), number);
+                assert!(text.starts_with(json["text"].as_str().unwrap()));
+            }
+        };
+        check(&tasks);
+        tasks.sort(SortKey::Due);
+        assert_eq!(tasks.task_json(0)["text"], "j");
+        check(&tasks);
+    }
+
+    #[test]
+    fn tasks_are_written_to_and_read_from_streams() {
+        let mut tasks = list(&["plain", "full"]);
+        let task = &mut tasks.tasks[1];
+        task.completed = Some(time("2024-01-03T00:00:00+01:00"));
+        task.labels = vec!["home".to_string(), "urgent".to_string()];
+        task.priority = 3;
+        task.history = vec!["old \"text\"".to_string()];
+        task.due = Some(time("2024-02-01T00:00:00+00:00"));
+        task.started = Some(time("2024-01-02T09:00:00+00:00"));
+        let loaded = round_trip(&tasks, &settings(b',', true));
+        assert_eq!(loaded.tasks.len(), 2);
+        for (loaded, task) in loaded.tasks.iter().zip(&tasks.tasks) {
+            assert_eq!(loaded.to_json(), task.to_json());
+        }
+        assert!(matches!(Tasks::from_reader(b"# td-format: x\n".as_slice(), &settings(b',', true)),
+            Err(TaskFileError::InvalidFormat)));
+    }
+
+    #[test]
+    fn skipped_records_are_reported() {
+        let path = temp_file("skipped.csv");
+        fs::write(&path, "text,created,completed\nkeep me,not-a-date,\nshort\ngood,2024-01-01T00:00:00+00:00,\n").unwrap();
+        let mut tasks = Tasks::new();
+        let skipped = tasks.load(&path, &FileSettings{strict: false, ..settings(b',', true)});
+        let strict = Tasks::new().load(&path, &settings(b',', true));
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(skipped, Ok(2)));
+        assert!(matches!(strict, Err(TaskFileError::ParseColmn(2, "created"))));
+        assert_eq!(texts(&tasks), vec!["good"]);
+    }
+
+    #[test]
+    fn repeated_bare_done_completes_the_next_task() {
+        let mut tasks = list(&["b", "a"]);
+        let selector = selector_from_string("", EmptyBehaviour::SelectLast).unwrap();
+        let now = time("2024-01-02T00:00:00+00:00");
+        assert_eq!(tasks.complete_by_selector(&*selector, now), 1);
+        assert_eq!(tasks.select(&*selector, DoneHandling::Hide), vec![1]);
+        assert_eq!(tasks.complete_by_selector(&*selector, now), 1);
+        assert!(tasks.tasks.iter().all(Task::is_completed));
+        assert_eq!(tasks.complete_by_selector(&*selector, now), 0);
+    }
+
+    #[test]
+    fn invalid_patterns_are_errors() {
+        assert!(matches!(selector_from_string("(", EmptyBehaviour::SelectAll),
+            Err(SelectorError::InvalidPattern(pattern)) if pattern == "("));
+        assert!(selector_from_string("(a|b)", EmptyBehaviour::SelectAll).is_ok());
+    }
+
+    #[test]
+    fn only_the_header_row_is_skipped() {
+        let content = "bad,nodate,\ngood,2024-01-01T00:00:00+00:00,\n";
+        assert!(matches!(Tasks::from_reader(content.as_bytes(), &settings(b',', true)),
+            Err(TaskFileError::ParseColmn(1, "created"))));
+        let old = "text,created,completed\nfirst,2024-01-01T00:00:00+00:00,\n";
+        assert_eq!(texts(&Tasks::from_reader(old.as_bytes(), &settings(b',', true)).unwrap()), vec!["first"]);
+    }
+
+    #[test]
+    fn negative_ages_are_rejected() {
+        assert_eq!(parse_age("90d"), Some(Duration::days(90)));
+        assert_eq!(parse_age("2w"), Some(Duration::weeks(2)));
+        assert_eq!(parse_age("3"), Some(Duration::days(3)));
+        assert_eq!(parse_age("-5d"), None);
+        assert_eq!(parse_age("-1"), None);
+    }
+
+    #[test]
+    fn bump_keeps_the_order_of_both_groups() {
+        let mut tasks = list(&["a", "b +errand", "c", "d +errand", "e"]);
+        let selected = tasks.select(&*selector_from_string(r"\+errand", EmptyBehaviour::SelectAll).unwrap(), DoneHandling::Hide);
+        tasks.bump(&selected);
+        assert_eq!(texts(&tasks), vec!["b +errand", "d +errand", "a", "c", "e"]);
+        assert!(tasks.dirty);
+    }
+
+    #[test]
+    fn commands_matching_nothing_keep_the_list_clean() {
+        let path = temp_file("clean.csv");
+        list(&["a", "b"]).save(&path, &settings(b',', true)).unwrap();
+        let mut tasks = Tasks::new();
+        tasks.load(&path, &settings(b',', true)).unwrap();
+        let nothing = selector_from_string("nothing", EmptyBehaviour::SelectAll).unwrap();
+        assert_eq!(tasks.complete_by_selector(&*nothing, time("2024-01-02T00:00:00+00:00")), 0);
+        assert_eq!(tasks.uncomplete_by_selector(&*nothing), 0);
+        tasks.bump(&tasks.select(&*nothing, DoneHandling::Hide));
+        assert!(!tasks.dirty);
+        let all = selector_from_string("", EmptyBehaviour::SelectAll).unwrap();
+        tasks.complete_by_selector(&*all, time("2024-01-02T00:00:00+00:00"));
+        assert!(tasks.dirty);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn reload_replaces_the_tasks() {
+        let path = temp_file("reload.csv");
+        list(&["a", "b"]).save(&path, &settings(b',', true)).unwrap();
+        let mut tasks = Tasks::new();
+        tasks.load(&path, &settings(b',', true)).unwrap();
+        list(&["c"]).save(&path, &settings(b',', true)).unwrap();
+        assert_eq!(tasks.reload(&path, &settings(b',', true)).unwrap(), 0);
+        assert_eq!(texts(&tasks), vec!["c"]);
+        std::fs::remove_file(&path).unwrap();
+        assert!(tasks.reload(&path, &settings(b',', true)).is_err());
+        assert_eq!(texts(&tasks), vec!["c"]);
+    }
+
+    #[test]
+    fn truncation_counts_the_number_and_done_marker() {
+        let mut texts = vec!["short"; 10];
+        texts.push("long task text");
+        let mut tasks = list(&texts);
+        tasks.complete(10, time("2024-01-02T00:00:00+00:00")).unwrap();
+        let view = View{truncate: Some(12), ..view()};
+        let line = tasks.format_task(10, &view);
+        assert_eq!(line, "11 X long t…");
+        assert_eq!(line.width(), 12);
+        assert_eq!(tasks.format_task(0, &view), " 1 short");
+    }
+
+    #[test]
+    fn tagged_selector_partitions_the_tasks() {
+        let mut tasks = list(&["buy milk +errand", "call mom", "weed beds @garden", "labeled", "email a@b"]);
+        tasks.tasks[3].labels.push("home".to_string());
+        assert_eq!(tasks.select(&TaggedSelector{tagged: true}, DoneHandling::Hide), vec![0, 2, 3]);
+        assert_eq!(tasks.select(&TaggedSelector{tagged: false}, DoneHandling::Hide), vec![1, 4]);
+    }
+
+    #[test]
+    fn compressed_files_round_trip() {
+        let path = temp_file("tasks.csv.gz");
+        let mut tasks = list(&["a", "b"]);
+        tasks.complete(1, time("2024-01-02T00:00:00+00:00")).unwrap();
+        tasks.save(&path, &settings(b',', true)).unwrap();
+        let content = std::fs::read(&path).unwrap();
+        assert_eq!(content[..2], [0x1f, 0x8b]);
+        let mut loaded = Tasks::new();
+        assert_eq!(loaded.load(&path, &settings(b',', true)).unwrap(), 0);
+        assert_eq!(texts(&loaded), vec!["a", "b"]);
+        assert!(loaded.tasks[1].is_completed());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn every_field_has_a_value() {
+        let mut task = Task::new("call +bank @phone".to_string(), time("2024-01-01T00:00:00+00:00"));
+        task.started = Some(time("2024-01-02T00:00:00+00:00"));
+        assert!(FIELDS.iter().all(|field| task.field(field).is_some()));
+        assert_eq!(task.field("tags").unwrap(), "+bank @phone");
+        assert_eq!(task.field("started").unwrap(), time("2024-01-02T00:00:00+00:00").format(TIME_FORMAT).to_string());
+    }
+
+    #[test]
+    fn edited_texts_are_stripped_of_control_characters() {
+        let mut tasks = list(&["a", "b"]);
+        tasks.apply_edit("41 [ ] red \u{1b}[31mtext\n42 [x] two\\nlines\n", false).unwrap();
+        assert_eq!(texts(&tasks), vec!["red [31mtext", "two\nlines"]);
+        assert!(tasks.tasks[1].is_completed());
+    }
+}
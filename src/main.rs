@@ -1,288 +1,1529 @@
-use std::{env, fmt::{Display, Formatter}, path::Path, fs};
+use std::{collections::HashMap, env, fmt::{Display, Formatter}, path::{Path, PathBuf}, fs, process, io::{self, IsTerminal, Write}, sync::mpsc, thread, time::Duration};
 
-use chrono::{DateTime, Local, FixedOffset};
+use chrono::{DateTime, Local, FixedOffset, Weekday};
 use directories::BaseDirs;
+use notify::{RecursiveMode, Watcher};
 use regex::Regex;
+use serde::Deserialize;
+use td::{AllSelector, AndSelector, Change, CompletedBetweenSelector, CompletedSelector, DoneHandling, EmptyBehaviour, FileSettings, FuzzySelector, IndexSelector, LabelSelector,
+    OrSelector, OutputFormat, PatternSelector, SETTABLE_FIELDS, SortKey, TaggedSelector, Task, TaskError, TaskFileError, TaskSelector, Tasks, View, FIELDS, LABEL_SEPARATOR, MAX_PRIORITY, TIME_FORMAT, check_file, is_overbroad, parse_age, parse_datetime,
+    parse_delimiter, selector_from_string, start_of_week};
 
-#[derive(Debug)]
-enum TaskError {
-    NotFound,
+/// Removes every occurrence of the flag from the arguments, returning whether
+/// it was given.
+fn take_flag(args: &mut Vec<String>, names: &[&str]) -> bool {
+    let count = args.len();
+    args.retain(|arg| !names.contains(&arg.as_str()));
+    args.len() != count
 }
 
-impl Display for TaskError {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Task not found")
+/// Narrows the tasks selected by a fuzzy selector down to the one with the
+/// best score, keeping other selections as they are.
+fn best_fuzzy_match(tasks: &Tasks, selected: Vec<usize>, selector: &str) -> Vec<usize> {
+    let Some(query) = selector.strip_prefix('~') else {
+        return selected;
+    };
+    let fuzzy = FuzzySelector::new(query);
+    let score = |task: &usize| fuzzy.score(&tasks.tasks[*task].text).unwrap_or(0);
+    // Prefer the earlier task when scores are equal.
+    let Some(best) = selected.iter().copied().min_by_key(|task| std::cmp::Reverse(score(task))) else {
+        return selected;
+    };
+    if selected.len() > 1 {
+        println!("Picked {} with score {} out of {} fuzzy matches", tasks.tasks[best], score(&best),
+            selected.len());
     }
+    vec![best]
 }
 
-struct Task {
-    text: String,
-    created: DateTime<FixedOffset>,
-    completed: Option<DateTime<FixedOffset>>,
-}
+const CLEAR_SCREEN: &str = "\x1b[2J\x1b[H";
 
-impl Task {
-    fn from_string(string: String) -> Task {
-        Task{text: string, created: DateTime::from(Local::now()) , completed: None}
+/// Calls `render` with the freshly loaded tasks whenever the task file changes.
+fn watch_file(path: &Path, settings: &FileSettings, mut render: impl FnMut(&Tasks)) -> notify::Result<()> {
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender)?;
+    // Watch the folder, as the file might be replaced instead of modified.
+    watcher.watch(path.parent().unwrap_or(path), RecursiveMode::NonRecursive)?;
+    let mut tasks = Tasks::new();
+    while let Ok(event) = receiver.recv() {
+        let event = event?;
+        if event.kind.is_access() || !event.paths.iter().any(|changed| changed == path) {
+            continue;
+        }
+        // Saving causes multiple events, wait until the file is written completely.
+        while receiver.recv_timeout(Duration::from_millis(100)).is_ok() {}
+        match tasks.reload(path, settings) {
+            Ok(_) => render(&tasks),
+            Err(error) => println!("Error loading tasks: {}", error),
+        }
     }
+    Ok(())
 }
 
-impl Display for Task {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        match self.completed.is_none() {
-            true => write!(f, "{}", self.text),
-            false => write!(f, "X {}", self.text),
+/// Counts down the minutes on a single line and rings the terminal bell when
+/// the time is up.
+fn countdown(minutes: u64) {
+    for left in (1..=minutes * 60).rev() {
+        print!("\r{:02}:{:02} left ", left / 60, left % 60);
+        if io::stdout().flush().is_err() {
+            return;
         }
+        thread::sleep(Duration::from_secs(1));
     }
+    println!("\rTime is up!\x07");
 }
 
-struct Tasks {
-    tasks: Vec<Task>
+/// Opens the text in the user's editor and returns the edited text.
+fn edit_in_editor(content: &str) -> io::Result<String> {
+    let path = env::temp_dir().join(format!("td-edit-{}.txt", process::id()));
+    fs::write(&path, content)?;
+    let editor = env::var("VISUAL").or_else(|_| env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_string());
+    // The editor might be given with arguments, like "code --wait".
+    let mut words = editor.split_whitespace();
+    let edited = process::Command::new(words.next().unwrap_or("vi"))
+        .args(words)
+        .arg(&path)
+        .status()
+        .and_then(|status| match status.success() {
+            true => fs::read_to_string(&path),
+            false => Err(io::Error::other("editor exited with an error")),
+        });
+    let _ = fs::remove_file(&path);
+    edited
 }
 
-trait TaskSelector {
-    fn matches(&self, tasks: &Tasks, index: usize) -> bool;
+/// Selects the tasks matched by any of the selectors in the file, which
+/// contains one selector per line.
+fn select_from_file(tasks: &Tasks, path: &Path, done: DoneHandling) -> io::Result<Vec<usize>> {
+    let mut selected = vec![];
+    for line in fs::read_to_string(path)?.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        let matched = match selector_from_string(line, EmptyBehaviour::SelectAll) {
+            Ok(selector) => tasks.select(&*selector, done),
+            Err(error) => {
+                println!("Warning: {}", error);
+                continue;
+            }
+        };
+        if matched.is_empty() {
+            println!("Warning: no task matches {}", line);
+        }
+        selected.extend(matched);
+    }
+    selected.sort_unstable();
+    selected.dedup();
+    Ok(selected)
 }
 
-struct AllSelector {}
+/// Reads one regex per line from the file into a selector matching any of them.
+/// Invalid patterns are skipped with a warning.
+fn patterns_from_file(path: &Path) -> io::Result<OrSelector> {
+    let mut selectors: Vec<Box<dyn TaskSelector>> = vec![];
+    for line in fs::read_to_string(path)?.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        match Regex::new(line) {
+            Ok(pattern) => selectors.push(Box::new(PatternSelector{pattern})),
+            Err(_) => println!("Warning: skipping invalid pattern {}", line),
+        }
+    }
+    Ok(OrSelector{selectors})
+}
 
-struct PatternSelector {
-    pattern: Regex
+/// Replaces a `:name` selector with the saved filter of that name, exiting if
+/// there is no such filter.
+fn expand_filter(filters: &HashMap<String, String>, selector: &str) -> String {
+    match selector.strip_prefix(':') {
+        Some(name) => match filters.get(name) {
+            Some(filter) => filter.clone(),
+            None => {
+                println!("Unknown filter: {}", name);
+                process::exit(1);
+            }
+        },
+        None => selector.to_string(),
+    }
 }
 
-struct RangeSelector {
-    from: usize,
-    to: usize,
+/// Parses a selector given on the command line, expanding saved filters.
+/// Exits if it refers to a task that doesn't exist.
+fn parse_selector(filters: &HashMap<String, String>, tasks: &Tasks, string: &str, empty: EmptyBehaviour)
+        -> Box<dyn TaskSelector> {
+    let selector = selector_from_string(&expand_filter(filters, string), empty)
+        .and_then(|selector| match string.is_empty() {
+            true => Ok(selector),
+            false => selector.validate(tasks).map(|_| selector),
+        });
+    selector.unwrap_or_else(|error| {
+        println!("{}", error);
+        process::exit(1);
+    })
 }
 
-struct IndexSelector {
-    index: usize
+/// Returns whether the expansion of an alias contains a word that looks like
+/// an option.
+fn has_option(expansion: &str) -> bool {
+    expansion.split_whitespace().any(|word| word.starts_with('-'))
 }
 
-impl TaskSelector for PatternSelector {
-    fn matches(&self, tasks: &Tasks, index: usize) -> bool {
-        if let Some(content) = &tasks.tasks.get(index) {
-            return self.pattern.is_match(&content.text)
+/// Short names for commands which can be replaced in the config file.
+const BUILTIN_ALIASES: [(&str, &str); 3] = [("a", "add"), ("d", "do"), ("x", "done")];
+
+/// Replaces the command with the one it is an alias for, which can be an alias
+/// itself. Exits if the aliases refer to each other in a loop.
+fn resolve_alias(args: &mut Vec<String>, aliases: &HashMap<String, String>) {
+    let mut seen: Vec<String> = vec![];
+    while let Some(command) = args.first() {
+        let expansion = match aliases.get(command) {
+            Some(expansion) => expansion.as_str(),
+            None => match BUILTIN_ALIASES.iter().find(|(alias, _)| alias == command) {
+                Some((_, expansion)) => expansion,
+                None => return,
+            },
+        };
+        if seen.contains(command) {
+            println!("Alias {} refers to itself", command);
+            process::exit(1);
+        }
+        // Options are parsed before aliases are replaced, so they would end up
+        // in the selector.
+        if has_option(expansion) {
+            println!("Alias {} contains an option, which has to be given on the command line instead", command);
+            process::exit(1);
         }
-        false
+        seen.push(command.clone());
+        let expansion: Vec<String> = expansion.split_whitespace().map(str::to_string).collect();
+        args.splice(..1, expansion);
     }
 }
 
-impl TaskSelector for IndexSelector {
-    fn matches(&self, _tasks: &Tasks, index: usize) -> bool {
-        index == self.index
+/// Removes the option from the arguments and parses its value as a date,
+/// exiting if it is invalid.
+fn take_date_option(args: &mut Vec<String>, names: &[&str]) -> Option<DateTime<FixedOffset>> {
+    let date = take_option(args, names)?;
+    match parse_datetime(&date) {
+        Some(date) => Some(date),
+        None => {
+            println!("Invalid date: {}", date);
+            process::exit(1);
+        }
     }
 }
 
-impl TaskSelector for RangeSelector {
-    fn matches(&self, _tasks: &Tasks, index: usize) -> bool {
-        index >= self.from && index <= self.to
+/// Name of the list used when no list is given.
+const DEFAULT_LIST: &str = "tasks";
+
+/// Returns the file storing the list with the given name. Names are limited
+/// to letters, digits, `-` and `_`, so they can't point outside of the folder.
+fn list_file(folder: &Path, name: &str) -> Option<PathBuf> {
+    if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_') {
+        return None;
     }
+    Some(folder.join(format!("{}.csv", name)))
 }
 
-impl TaskSelector for AllSelector {
-    fn matches(&self, _tasks: &Tasks, _index: usize) -> bool {
-        true
+/// Expands a leading `~` to the home folder and `$NAME` or `${NAME}` to the
+/// value of the environment variable. Unknown variables are kept as they are.
+fn expand_path(path: &str, home: Option<&Path>) -> PathBuf {
+    let path = match (path.strip_prefix('~'), home) {
+        (Some(rest), Some(home)) if rest.is_empty() || rest.starts_with('/') =>
+            format!("{}{}", home.display(), rest),
+        _ => path.to_string(),
+    };
+    let mut expanded = String::new();
+    let mut rest = path.as_str();
+    while let Some(start) = rest.find('$') {
+        expanded.push_str(&rest[..start]);
+        let variable = &rest[start + 1..];
+        let (name, after) = match variable.strip_prefix('{').and_then(|v| v.split_once('}')) {
+            Some((name, after)) => (name, after),
+            None => {
+                let end = variable.find(|c: char| !c.is_alphanumeric() && c != '_')
+                    .unwrap_or(variable.len());
+                (&variable[..end], &variable[end..])
+            }
+        };
+        match env::var(name) {
+            Ok(value) if !name.is_empty() => expanded.push_str(&value),
+            _ => expanded.push_str(&rest[start..rest.len() - after.len()]),
+        }
+        rest = after;
     }
+    expanded.push_str(rest);
+    PathBuf::from(expanded)
 }
 
-enum EmptyBehaviour {
-    SelectLast,
-    SelectAll,
+/// Returns the text of the task added by the arguments, or `None` if the
+/// command is an unknown option. Mistyped flags like --hlep would otherwise
+/// become tasks, while `td add` still creates tasks starting with a dash.
+fn task_text(args: &[String], rest: &str) -> Option<String> {
+    match args.first().map(String::as_str) {
+        Some("add") => Some(rest.to_string()),
+        Some(action) if action.starts_with('-') => None,
+        _ => Some(args.join(" ")),
+    }
 }
 
-enum DoneHandling {
-    Show,
-    Hide,
+/// Appends a line with the time, the command and the ids of the changed
+/// tasks, separated by tabs, to the audit log.
+fn record_action(log: &Path, command: &str, ids: &[u32]) -> io::Result<()> {
+    let mut file = fs::OpenOptions::new().create(true).append(true).open(log)?;
+    let ids: Vec<String> = ids.iter().map(|id| id.to_string()).collect();
+    writeln!(file, "{}\t{}\t{}", Local::now().format(TIME_FORMAT), command.replace(['\t', '\n'], " "),
+        ids.join(","))
 }
 
-fn selector_from_string(string: &String, empty: EmptyBehaviour) -> Box<dyn TaskSelector> {
-    if string == "" {
-        match empty {
-            EmptyBehaviour::SelectLast => return Box::new(IndexSelector{index: 0}),
-            EmptyBehaviour::SelectAll => return Box::new(AllSelector{})
+/// Prints the audit log written by `record_action`.
+fn print_actions(log: &Path) -> io::Result<()> {
+    for line in fs::read_to_string(log)?.lines() {
+        let mut columns = line.splitn(3, '\t');
+        let (Some(time), Some(command), ids) = (columns.next(), columns.next(), columns.next()) else {
+            continue;
+        };
+        let time = DateTime::parse_from_str(time, TIME_FORMAT)
+            .map(|time| time.with_timezone(&Local).format("%Y-%m-%d %H:%M").to_string())
+            .unwrap_or_else(|_| time.to_string());
+        let ids: Vec<String> = ids.unwrap_or("").split(',').filter(|id| !id.is_empty())
+            .map(|id| format!("#{}", id)).collect();
+        match ids.is_empty() {
+            true => println!("{}  td {}", time, command),
+            false => println!("{}  td {}  ({})", time, command, ids.join(", ")),
         }
     }
-    if let Ok(index) = string.parse::<u32>() {
-        return Box::new(IndexSelector{index: index as usize - 1});
+    Ok(())
+}
+
+/// Commands which only show tasks, after which the file isn't saved.
+const READ_ONLY_COMMANDS: [&str; 11] = ["show", "status", "nth", "first", "stale", "peek", "diff", "labels", "stats", "doctor", "check"];
+
+/// Age in days after which `td stale` lists a task.
+const DEFAULT_STALE_DAYS: i64 = 30;
+
+/// Hours before the due date from which tasks are colored yellow.
+const DEFAULT_DUE_SOON_HOURS: i64 = 24;
+
+/// Number of tasks a destructive command may affect before asking for confirmation.
+const CONFIRM_THRESHOLD: usize = 5;
+
+/// Asks the user a yes/no question on stdin. When stdin isn't a terminal the
+/// question is skipped so scripts never block.
+fn confirm(question: &str) -> bool {
+    if !io::stdin().is_terminal() {
+        return true;
+    }
+    print!("{} [y/N] ", question);
+    let mut answer = String::new();
+    if io::stdout().flush().and_then(|_| io::stdin().read_line(&mut answer)).is_err() {
+        return false;
     }
-    if let Some((a, b)) = string.split_once("-") {
-        if let (Ok(a), Ok(b)) = (a.parse::<u32>(), b.parse::<u32>()) {
-            return Box::new(RangeSelector{from:(a as usize - 1), to:(b as usize - 1)});
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+/// Removes the option and its value from the arguments, returning the value of
+/// the last occurrence.
+fn take_option(args: &mut Vec<String>, names: &[&str]) -> Option<String> {
+    let mut value = None;
+    while let Some(position) = args.iter().position(|arg| names.contains(&arg.as_str())) {
+        args.remove(position);
+        if position < args.len() {
+            value = Some(args.remove(position));
         }
     }
-    Box::new(PatternSelector{pattern: Regex::new(string).expect("Invalid regex")})
+    value
 }
 
-enum TaskFileError {
-    NotFound,
-    MissingColumn,
-    ParseColmn,
-    WriteColumn,
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct Config {
+    truncate: Option<usize>,
+    /// Move completed tasks to the end of the list.
+    sink_completed: bool,
+    /// Mark tasks older than this many days in listings.
+    stale_days: Option<i64>,
+    /// Day the week starts on, like "monday" or "sunday".
+    week_start: Option<String>,
+    /// Color tasks due within this many hours yellow.
+    due_soon_hours: Option<i64>,
+    /// Character separating the columns of the task file.
+    delimiter: Option<String>,
+    /// Save the task file without the header row.
+    no_header: bool,
+    /// Remember the previous texts of reworded tasks.
+    keep_history: bool,
+    /// Record every change of the list in `actions.log`.
+    audit: bool,
+    /// Text that can be put in front of new tasks using `--template`.
+    templates: HashMap<String, String>,
+    /// Selectors that can be used as `:name`.
+    filters: HashMap<String, String>,
+    /// Other names for commands, like `ls = "show"`.
+    aliases: HashMap<String, String>,
 }
 
-impl Display for TaskFileError {
+enum ConfigError {
+    Read,
+    Parse(toml::de::Error),
+    Write,
+    UnknownKey(String),
+    InvalidValue(String, &'static str),
+}
+
+impl Display for ConfigError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", match self {
-            TaskFileError::NotFound => "Task file not found",
-            TaskFileError::MissingColumn => "Task file missing column",
-            TaskFileError::ParseColmn => "Failed to parse task",
-            TaskFileError::WriteColumn => "Failed to write task",
-        })
+        match self {
+            ConfigError::Read => write!(f, "Failed to read config file"),
+            ConfigError::Parse(error) => write!(f, "Invalid config file: {}", error),
+            ConfigError::Write => write!(f, "Failed to write config file"),
+            ConfigError::UnknownKey(key) => write!(f, "Unknown setting {}", key),
+            ConfigError::InvalidValue(key, expected) => write!(f, "Invalid value for {}, expected {}", key, expected),
+        }
     }
 }
 
-const TIME_FORMAT: &str = "%+";
-//const TIME_FORMAT: &str = "%Y-%m-%dT%H:%M:%S";
-
-impl Tasks {
-    fn load(&mut self, path: &Path) -> Result<(), TaskFileError> {
-        for result in csv::Reader::from_path(path).map_err(|_| TaskFileError::NotFound)?.records() {
-            if let Ok(record) = result {
-                if record.len() < 3 {
-                    return Err(TaskFileError::MissingColumn);
-                }
-                self.tasks.push(Task{
-                    text: record[0].to_string(),
-                    created: DateTime::parse_from_str(&record[1], TIME_FORMAT).map_err(|_| TaskFileError::ParseColmn)?,
-                    completed: DateTime::parse_from_str(&record[2], TIME_FORMAT).ok(),
-                })
-            }
-        }
-        Ok(())
+/// Kind of value a setting takes.
+enum ValueKind {
+    Number,
+    Switch,
+    Text,
+}
+
+/// Settings which can be read and changed with `td config`.
+const CONFIG_KEYS: [(&str, ValueKind); 9] = [
+    ("truncate", ValueKind::Number),
+    ("sink_completed", ValueKind::Switch),
+    ("stale_days", ValueKind::Number),
+    ("due_soon_hours", ValueKind::Number),
+    ("week_start", ValueKind::Text),
+    ("delimiter", ValueKind::Text),
+    ("no_header", ValueKind::Switch),
+    ("keep_history", ValueKind::Switch),
+    ("audit", ValueKind::Switch),
+];
+
+/// Tables of the config file whose entries are given as `table.name`.
+const CONFIG_TABLES: [&str; 3] = ["templates", "filters", "aliases"];
+
+/// Returns the kind of value the setting takes.
+fn config_kind(key: &str) -> Result<&'static ValueKind, ConfigError> {
+    match key.split_once('.') {
+        Some((table, name)) if CONFIG_TABLES.contains(&table) && !name.is_empty() => Ok(&ValueKind::Text),
+        _ => CONFIG_KEYS.iter().find(|(name, _)| *name == key).map(|(_, kind)| kind)
+            .ok_or_else(|| ConfigError::UnknownKey(key.to_string())),
     }
+}
 
-    fn save(self, path: &Path) -> Result<(), TaskFileError> {
-        if let Ok(mut writer) = csv::Writer::from_path(path) {
-            writer.write_record(["text", "created", "completed"])
-                .map_err(|_| TaskFileError::WriteColumn)?;
-            for task in self.tasks {
-                writer.write_record([
-                    task.text,
-                    task.created.format(TIME_FORMAT).to_string(),
-                    match task.completed {
-                        Some(time) => time.format(TIME_FORMAT).to_string(),
-                        _ => "".to_string(),
-                    }
-                ]).map_err(|_| TaskFileError::WriteColumn)?;
-            }
-            writer.flush().map_err(|_| TaskFileError::WriteColumn)?;
-        } else {
-            return Err(TaskFileError::NotFound);
-        }
-        Ok(())
+/// Parses the value of a setting given on the command line.
+fn parse_config_value(key: &str, value: &str) -> Result<toml::Value, ConfigError> {
+    let invalid = |expected| ConfigError::InvalidValue(key.to_string(), expected);
+    match (config_kind(key)?, key) {
+        (ValueKind::Number, _) => value.parse::<u32>().map(|number| toml::Value::Integer(number.into()))
+            .map_err(|_| invalid("a positive number")),
+        (ValueKind::Switch, _) => value.parse().map(toml::Value::Boolean).map_err(|_| invalid("true or false")),
+        (ValueKind::Text, "delimiter") if parse_delimiter(value).is_none() => Err(invalid("a single character or tab")),
+        (ValueKind::Text, "week_start") if value.parse::<Weekday>().is_err() => Err(invalid("a day like monday")),
+        (ValueKind::Text, key) if key.starts_with("aliases.") && has_option(value) =>
+            Err(invalid("a command without options")),
+        (ValueKind::Text, _) => Ok(toml::Value::String(value.to_string())),
     }
-    
-    fn print_task(&self, task: usize) {
-        println!("{} {}", task + 1, self.tasks[task]);
+}
+
+impl Config {
+    /// Loads the config file, falling back to the defaults if it doesn't exist.
+    fn load(path: &Path) -> Result<Config, ConfigError> {
+        if !path.exists() {
+            return Ok(Config::default());
+        }
+        let content = fs::read_to_string(path).map_err(|_| ConfigError::Read)?;
+        toml::from_str(&content).map_err(ConfigError::Parse)
     }
 
-    fn status(&self) {
-        println!("Tasks:");
-        for (num, task) in self.tasks.iter().enumerate() {
-            if task.completed.is_none() {
-                self.print_task(num);
-            }
+    /// Reads the config file as a table, which is empty if the file doesn't
+    /// exist.
+    fn load_table(path: &Path) -> Result<toml::value::Table, ConfigError> {
+        if !path.exists() {
+            return Ok(toml::value::Table::new());
         }
+        let content = fs::read_to_string(path).map_err(|_| ConfigError::Read)?;
+        toml::from_str(&content).map_err(ConfigError::Parse)
+    }
+
+    /// Returns the value of a setting in the config file, or `None` if it
+    /// isn't set.
+    fn get(path: &Path, key: &str) -> Result<Option<toml::Value>, ConfigError> {
+        config_kind(key)?;
+        let table = Config::load_table(path)?;
+        Ok(match key.split_once('.') {
+            Some((section, name)) => table.get(section).and_then(|section| section.get(name)).cloned(),
+            None => table.get(key).cloned(),
+        })
     }
 
-    fn select(&self, selector: &(impl TaskSelector + ?Sized), done: DoneHandling) -> Vec<usize> {
-        let mut selected: Vec<usize> = vec![];
-        for (task_num, task) in self.tasks.iter().enumerate() {
-            if match done {
-                        DoneHandling::Show => true,
-                        DoneHandling::Hide => task.completed.is_none(),
-                    } {
-                if selector.matches(&self, task_num) {
-                    selected.push(task_num);
+    /// Changes a setting in the config file, creating the file if needed.
+    /// Comments in the file are lost.
+    fn set(path: &Path, key: &str, value: &str) -> Result<(), ConfigError> {
+        let value = parse_config_value(key, value)?;
+        let mut table = Config::load_table(path)?;
+        match key.split_once('.') {
+            Some((section, name)) => match table.entry(section)
+                    .or_insert_with(|| toml::Value::Table(toml::value::Table::new())) {
+                toml::Value::Table(section) => {
+                    section.insert(name.to_string(), value);
                 }
+                _ => return Err(ConfigError::InvalidValue(section.to_string(), "a table")),
+            },
+            None => {
+                table.insert(key.to_string(), value);
             }
         }
-        selected
+        // Serializing a value puts the tables after the other settings as required.
+        let content = toml::to_string(&toml::Value::Table(table)).map_err(|_| ConfigError::Write)?;
+        if let Some(folder) = path.parent() {
+            fs::create_dir_all(folder).map_err(|_| ConfigError::Write)?;
+        }
+        fs::write(path, content).map_err(|_| ConfigError::Write)
     }
+}
 
-    fn create(&mut self, task: Task) {
-        println!("Created new task: {}", task);
-        self.tasks.insert(0, task)
+/// Returns the archive of the task file, like `tasks.archive.csv` for
+/// `tasks.csv`. Archives of compressed task files are compressed too, and a
+/// compressed archive is used instead of a plain one if it exists.
+fn archive_file(tasks_file: &Path) -> PathBuf {
+    if let Some(uncompressed) = tasks_file.to_str().and_then(|path| path.strip_suffix(".gz")) {
+        return Path::new(uncompressed).with_extension("archive.csv.gz");
     }
-
-    fn work_on(&mut self, task: usize) -> Result<(),TaskError> {
-        if let Some(content) = self.tasks.get(task) {
-            println!("Working on {}!", content);
-            let working = self.tasks.remove(task);
-            self.tasks.insert(0, working);
-            Ok(())
-        } else {
-            Err(TaskError::NotFound)
-        }
+    let compressed = tasks_file.with_extension("archive.csv.gz");
+    match compressed.exists() {
+        true => compressed,
+        false => tasks_file.with_extension("archive.csv"),
     }
+}
 
-    fn complete(&mut self, num: usize) -> Result<(),TaskError> {
-        if self.tasks.get(num).is_some() {
-            let mut task = self.tasks.remove(num);
-            println!("Completed {}!", task);
-            task.completed = Some(DateTime::from(Local::now()));
-            self.tasks.push(task);
-            Ok(())
-        } else {
-            Err(TaskError::NotFound)
-        }
+/// Returns the folder storing the lists, creating it if needed. Exits if the
+/// folder is unknown because the home folder couldn't be determined.
+fn data_folder(base_dirs: &Option<BaseDirs>) -> PathBuf {
+    let Some(base_dirs) = base_dirs else {
+        println!("Couldn't find the home folder to store the tasks in. \
+            Set $HOME or choose a task file with --file or TD_FILE.");
+        process::exit(1);
+    };
+    let folder = base_dirs.data_local_dir().join("td-todo");
+    if !folder.exists() {
+        fs::create_dir_all(&folder).expect("Couldn't create application folder");
     }
+    folder
 }
 
 fn main() {
-    let user_dir = BaseDirs::new().unwrap().data_local_dir().join("td-todo");
-    if !user_dir.exists() {
-        fs::create_dir_all(&user_dir).expect("Couldn't create application folder");
-    }
-    let tasks_file = user_dir.clone().join("tasks.csv");
-    let mut tasks = Tasks{tasks:vec![]};
-    if let Err(error) = tasks.load(&tasks_file) {
-        println!("Error loading tasks: {}", error);
-    }
+    // Minimal systems might not have a home folder, which is fine as long as
+    // a task file is given.
+    let base_dirs = BaseDirs::new();
+    let config_file = base_dirs.as_ref().map(|base_dirs| base_dirs.config_dir().join("td-todo").join("config.toml"));
     let mut args: Vec<String> = env::args().collect();
     args.remove(0);
+    // Managing the config doesn't need the tasks, and shouldn't fail because
+    // the config is invalid.
+    if args.first().is_some_and(|action| action == "config") {
+        let Some(config_file) = config_file else {
+            println!("Couldn't find the home folder to store the config in");
+            process::exit(1);
+        };
+        match args[1..].iter().map(String::as_str).collect::<Vec<_>>()[..] {
+            ["path"] => println!("{}", config_file.display()),
+            ["get", key] => match Config::get(&config_file, key) {
+                Ok(Some(toml::Value::String(value))) => println!("{}", value),
+                Ok(Some(value)) => println!("{}", value),
+                Ok(None) => process::exit(1),
+                Err(error) => {
+                    println!("{}", error);
+                    process::exit(1);
+                }
+            },
+            ["set", key, ref value @ ..] if !value.is_empty() => {
+                if let Err(error) = Config::set(&config_file, key, &value.join(" ")) {
+                    println!("{}", error);
+                    process::exit(1);
+                }
+            }
+            _ => {
+                println!("Usage: td config path|get <key>|set <key> <value>");
+                process::exit(1);
+            }
+        }
+        return;
+    }
+    let config = match &config_file {
+        Some(config_file) => Config::load(config_file).unwrap_or_else(|error| {
+            println!("Error loading config: {}", error);
+            Config::default()
+        }),
+        None => Config::default(),
+    };
+    let command = args.join(" ");
+    let list = take_option(&mut args, &["--list"]).unwrap_or_else(|| DEFAULT_LIST.to_string());
+    let file = take_option(&mut args, &["--file", "-f"]).or_else(|| env::var("TD_FILE").ok())
+        .map(|file| expand_path(&file, base_dirs.as_ref().map(BaseDirs::home_dir)));
+    let tasks_file = match &file {
+        Some(file) => file.clone(),
+        None => match list_file(&data_folder(&base_dirs), &list) {
+            Some(file) => file,
+            None => {
+                println!("Invalid list name: {}", list);
+                process::exit(1);
+            }
+        },
+    };
+    let format = if take_flag(&mut args, &["--porcelain"]) {
+        OutputFormat::Porcelain
+    } else if take_flag(&mut args, &["--jsonl"]) {
+        OutputFormat::JsonLines
+    } else if take_flag(&mut args, &["--json"]) {
+        OutputFormat::Json
+    } else {
+        OutputFormat::Text
+    };
+    let truncate = match take_option(&mut args, &["--truncate", "--width"]) {
+        // Fit the lines to the terminal, but don't clip output for other
+        // programs.
+        Some(width) if width == "auto" => match io::stdout().is_terminal() {
+            true => terminal_size::terminal_size().map(|(width, _)| width.0 as usize),
+            false => None,
+        },
+        Some(width) => match width.parse() {
+            Ok(width) => Some(width),
+            Err(_) => {
+                println!("Invalid width: {}", width);
+                return;
+            }
+        },
+        None => config.truncate,
+    };
+    let created = take_date_option(&mut args, &["--created"]);
+    let at = take_date_option(&mut args, &["--at"]);
+    let due = take_date_option(&mut args, &["--due"]);
+    let priority = take_option(&mut args, &["--priority"]).map(|priority| {
+        match priority.parse().ok().filter(|priority| *priority <= MAX_PRIORITY) {
+            Some(priority) => priority,
+            None => {
+                println!("Invalid priority {}, expected a number from 0 to {}", priority, MAX_PRIORITY);
+                process::exit(1);
+            }
+        }
+    });
+    let template = take_option(&mut args, &["--template"]);
+    let multiline = take_flag(&mut args, &["--multiline"]);
+    let after = take_option(&mut args, &["--after"]);
+    let before = take_option(&mut args, &["--before"]);
+    let explain = take_flag(&mut args, &["--explain"]);
+    let fuzzy = take_flag(&mut args, &["--fuzzy"]);
+    let field = take_option(&mut args, &["--field"]);
+    if let Some(field) = &field {
+        if !FIELDS.contains(&field.as_str()) {
+            println!("Unknown field {}, expected one of {}", field, FIELDS.join(", "));
+            process::exit(1);
+        }
+    }
+    let watch = take_flag(&mut args, &["--watch"]);
+    let append = take_flag(&mut args, &["--append"]);
+    let plain = take_flag(&mut args, &["--plain"]);
+    let numbered = !take_flag(&mut args, &["--no-number"]);
+    let quiet = take_flag(&mut args, &["--quiet", "-q"]);
+    let raw_time = take_flag(&mut args, &["--raw-time"]);
+    // Colors would end up as garbage in files and other programs.
+    let due_soon = chrono::Duration::hours(config.due_soon_hours.unwrap_or(DEFAULT_DUE_SOON_HOURS));
+    let highlight_due = match (take_flag(&mut args, &["--highlight-overdue"]), take_flag(&mut args, &["--no-color"])) {
+        (_, true) => None,
+        (true, false) => Some(due_soon),
+        (false, false) => (io::stdout().is_terminal() && env::var_os("NO_COLOR").is_none()).then_some(due_soon),
+    };
+    // Show which list is used unless it is the default one.
+    let context = match &file {
+        Some(file) => Some(file.display().to_string()),
+        None => (take_flag(&mut args, &["--context"]) || list != DEFAULT_LIST).then(|| list.clone()),
+    };
+    let pretty = take_flag(&mut args, &["--pretty"]);
+    let columns = take_option(&mut args, &["--columns"]);
+    // Columns only make sense when a person reads the output.
+    let layout = match columns.as_deref() {
+        Some("auto") if io::stdout().is_terminal() =>
+            terminal_size::terminal_size().map(|(width, _)| width.0 as usize),
+        _ => None,
+    };
+    let view = View{format, truncate, plain, numbered, quiet, raw_time, highlight_due, stale_days: config.stale_days, context, pretty, columns: layout};
+    let strict = take_flag(&mut args, &["--strict"]);
+    let delimiter = match take_option(&mut args, &["--delimiter"]).or(config.delimiter) {
+        Some(delimiter) => match parse_delimiter(&delimiter) {
+            Some(delimiter) => delimiter,
+            None => {
+                println!("Invalid delimiter: {}", delimiter);
+                return;
+            }
+        },
+        None => b',',
+    };
+    let header = !take_flag(&mut args, &["--no-header"]) && !config.no_header;
+    // Progress output would only clutter logs and pipes.
+    let progress = !quiet && io::stderr().is_terminal();
+    let verbose = take_flag(&mut args, &["--verbose", "-v"]);
+    let no_save = take_flag(&mut args, &["--no-save"]);
+    let settings = FileSettings{strict, delimiter, header, progress, verbose};
+    let mut tasks = Tasks::new();
+    // Saving a partly loaded list would overwrite the tasks that are missing.
+    let mut load_failed = false;
+    match tasks.load(&tasks_file, &settings) {
+        Ok(0) => {}
+        Ok(skipped) => {
+            println!("Warning: skipped {} malformed tasks in the task file", skipped);
+            load_failed = true;
+        }
+        Err(error) => {
+            println!("Error loading tasks: {}", error);
+            load_failed = !matches!(error, TaskFileError::NotFound);
+            if strict && load_failed {
+                process::exit(1);
+            }
+        }
+    }
+    // Remember the loaded tasks to find out which ones were changed.
+    let loaded = config.audit.then(|| tasks.tasks.iter().cloned().collect::<Tasks>());
+    let yes = take_flag(&mut args, &["--yes", "-y"]);
+    let from_file = take_option(&mut args, &["--from-file"]);
+    let pattern_file = take_option(&mut args, &["--pattern-file"]);
+    let completed_between = args.iter().position(|arg| arg == "--completed-between").map(|position| {
+        let dates: Vec<String> = args.drain(position..(position + 3).min(args.len())).skip(1).collect();
+        let [start, end] = dates.as_slice() else {
+            println!("Usage: --completed-between <start> <end>");
+            process::exit(1);
+        };
+        let parse = |date: &String| parse_datetime(date).unwrap_or_else(|| {
+            println!("Invalid date: {}", date);
+            process::exit(1);
+        });
+        // Include the whole last day unless a time is given.
+        let end_time = match end.contains(':') {
+            true => parse(end),
+            false => parse(end) + chrono::Duration::days(1),
+        };
+        let start = parse(start);
+        if start >= end_time {
+            println!("The start of the range has to be before its end");
+            process::exit(1);
+        }
+        CompletedBetweenSelector{start, end: end_time}
+    });
+    let group_by_tag = match take_option(&mut args, &["--group-by"]).as_deref() {
+        Some("tag") => true,
+        Some(group) => {
+            println!("Unknown grouping {}, expected tag", group);
+            process::exit(1);
+        }
+        None => false,
+    };
+    let undo_last = take_flag(&mut args, &["--undo-last"]);
+    let timer = take_option(&mut args, &["--timer"]).map(|minutes| match minutes.parse::<u64>() {
+        Ok(minutes) => minutes,
+        Err(_) => {
+            println!("Invalid number of minutes: {}", minutes);
+            process::exit(1);
+        }
+    });
+    // The id of the task to count down the timer for once the list is saved.
+    let mut timed_task = None;
+    let done = take_flag(&mut args, &["--done"]);
+    let sink = take_flag(&mut args, &["--sink"]) || config.sink_completed;
+    let editor = take_flag(&mut args, &["--editor"]);
+    let label = take_option(&mut args, &["--label"]);
+    let tagged = match (take_flag(&mut args, &["--only-tagged"]), take_flag(&mut args, &["--untagged"])) {
+        (true, true) => {
+            println!("--only-tagged and --untagged can't be used together");
+            process::exit(1);
+        }
+        (tagged, untagged) => (tagged || untagged).then_some(tagged),
+    };
+    let grouped = take_flag(&mut args, &["--grouped"]);
+    let older_than = take_option(&mut args, &["--older-than"]);
+    let all_fields = take_flag(&mut args, &["--all-fields"]);
+    let envelope = take_flag(&mut args, &["--schema", "--envelope"]);
+    let csv = take_flag(&mut args, &["--csv"]);
+    let weekly = take_flag(&mut args, &["--weekly"]);
+    // The flag given last wins.
+    let completed = match (args.iter().rposition(|arg| arg == "--done-only"),
+            args.iter().rposition(|arg| arg == "--active-only")) {
+        (Some(done), Some(active)) => Some(done > active),
+        (done, active) => done.map(|_| true).or(active.map(|_| false)),
+    };
+    take_flag(&mut args, &["--done-only", "--active-only"]);
+    resolve_alias(&mut args, &config.aliases);
+    // Other commands either only work on unfinished tasks or don't list any.
+    if let (Some(_), Some(action)) = (completed, args.first()) {
+        if !["show", "status", "peek"].contains(&action.as_str()) {
+            println!("--done-only and --active-only can only be used with show, status and peek");
+            process::exit(1);
+        }
+    }
+    // Numbers refer to the sorted list in this invocation, the file keeps
+    // its order.
+    let stored_order = match take_option(&mut args, &["--sort"]) {
+        Some(key) => {
+            let Some(key) = SortKey::parse(&key) else {
+                println!("Unknown sort order {}, expected created, text, priority, id or due", key);
+                process::exit(1);
+            };
+            if let Some(action @ ("do" | "bump" | "move-up" | "move-down" | "edit")) = args.first().map(String::as_str) {
+                println!("--sort can't be used with {}, which changes the order of the list", action);
+                process::exit(1);
+            }
+            let ids: Vec<u32> = tasks.tasks.iter().map(|task| task.id).collect();
+            tasks.sort(key);
+            Some(ids)
+        }
+        None => None,
+    };
+    if load_failed && args.first().is_some_and(|action| !READ_ONLY_COMMANDS.contains(&action.as_str())) {
+        println!("Not changing the tasks, the task file couldn't be loaded completely");
+        process::exit(1);
+    }
     match args.first() {
         Some(action) => {
-            let rest = &args[1..].join(" ");
+            let rest = &match args[1..].join(" ") {
+                rest if fuzzy && !rest.is_empty() => format!("~{}", rest),
+                rest => rest,
+            };
             match action.as_str() {
+                "done" if undo_last => {
+                    match tasks.undo_last_completion() {
+                        Ok(()) => tasks.status(&view),
+                        Err(_) => println!("No completed tasks"),
+                    }
+                }
                 "done" => {
-                    tasks.select(
-                        &*selector_from_string(rest, EmptyBehaviour::SelectLast),
-                        DoneHandling::Hide)
-                        .iter()
-                        .for_each(|t| tasks.complete(*t)
-                        .unwrap());
-                    tasks.status();
+                    let selected = match &from_file {
+                        Some(path) => match select_from_file(&tasks, Path::new(path), DoneHandling::Hide) {
+                            Ok(selected) => selected,
+                            Err(error) => {
+                                println!("Error reading selectors: {}", error);
+                                process::exit(1);
+                            }
+                        },
+                        None => best_fuzzy_match(&tasks, tasks.select(
+                            &*parse_selector(&config.filters, &tasks, rest, EmptyBehaviour::SelectLast),
+                            DoneHandling::Hide), rest),
+                    };
+                    let question = match from_file.is_none() && is_overbroad(&expand_filter(&config.filters, rest)) {
+                        true if selected.len() > 1 => Some(format!(
+                            "'{}' matches almost any task, about to complete {} tasks. Continue?", rest, selected.len())),
+                        _ if selected.len() > CONFIRM_THRESHOLD => Some(format!(
+                            "About to complete {} tasks. Continue?", selected.len())),
+                        _ => None,
+                    };
+                    if question.is_some_and(|question| !yes && !confirm(&question)) {
+                        println!("Aborted");
+                        return;
+                    }
+                    let time = at.unwrap_or_else(|| DateTime::from(Local::now()));
+                    // Describe the tasks before they get the done marker.
+                    let texts: Vec<String> = selected.iter().map(|task| tasks.tasks[*task].to_string()).collect();
+                    tasks.complete_by_selector(&OrSelector{selectors: selected.iter()
+                        .map(|index| Box::new(IndexSelector{index: *index}) as Box<dyn TaskSelector>).collect()}, time);
+                    let mut completed = vec![];
+                    for (task, text) in selected.into_iter().zip(texts) {
+                        match tasks.tasks[task].completed == Some(time) {
+                            true => completed.push(text),
+                            false => println!("Error completing {}: {}", text, TaskError::CompletedBeforeCreated),
+                        }
+                    }
+                    if sink {
+                        tasks.sink_completed();
+                    }
+                    match completed.as_slice() {
+                        _ if quiet => {}
+                        [] => {}
+                        [task] => println!("Completed {}!", task),
+                        tasks => {
+                            println!("Completed {} tasks:", tasks.len());
+                            tasks.iter().for_each(|task| println!("  {}", task));
+                        }
+                    }
+                    tasks.status(&view);
+                }
+                "reopen" => {
+                    if rest.is_empty() {
+                        println!("Usage: td reopen <selector>");
+                        process::exit(1);
+                    }
+                    let selector = parse_selector(&config.filters, &tasks, rest, EmptyBehaviour::SelectAll);
+                    let selected: Vec<usize> = tasks.select(&*selector, DoneHandling::Show).into_iter()
+                        .filter(|task| tasks.tasks[*task].is_completed())
+                        .collect();
+                    tasks.uncomplete_by_selector(&*selector);
+                    match selected.as_slice() {
+                        _ if quiet => {}
+                        [] => println!("No completed task matches {}", rest),
+                        [task] => println!("Reopened {}", tasks.tasks[*task]),
+                        selected => {
+                            println!("Reopened {} tasks:", selected.len());
+                            selected.iter().for_each(|task| println!("  {}", tasks.tasks[*task]));
+                        }
+                    }
                 }
                 "do" => {
-                    match tasks.select(
-                            &*selector_from_string(rest, EmptyBehaviour::SelectLast),
-                            DoneHandling::Hide)
-                            .first() {
+                    let selected = best_fuzzy_match(&tasks, tasks.select(
+                            &*parse_selector(&config.filters, &tasks, rest, EmptyBehaviour::SelectLast),
+                            DoneHandling::Hide), rest);
+                    if selected.len() > 1 {
+                        println!("Warning: do only works on one task, using the first of {} matches",
+                            selected.len());
+                    }
+                    match selected.first() {
                         Some(task) => {
-                            if let Err(error) = tasks.work_on(*task) {
-                                println!("Error doing task: {error}")
+                            match tasks.work_on(*task, DateTime::from(Local::now())) {
+                                Ok(()) if !quiet => println!("({} active tasks remaining)", tasks.counts().0),
+                                Ok(()) => {}
+                                Err(error) => println!("Error doing task: {error}"),
+                            }
+                            // The timer needs somebody watching it.
+                            if timer.is_some() && io::stdin().is_terminal() && io::stdout().is_terminal() {
+                                timed_task = tasks.tasks.first().map(|task| task.id);
+                            }
+                        },
+                        None => println!("Task not found"),
+                    }
+                }
+                "show" => {
+                    let mut selector = parse_selector(&config.filters, &tasks, rest, EmptyBehaviour::SelectAll);
+                    if let Some(label) = label {
+                        selector = Box::new(AndSelector{selectors: vec![
+                            selector, Box::new(LabelSelector{label})]});
+                    }
+                    if let Some(completed) = completed {
+                        selector = Box::new(AndSelector{selectors: vec![
+                            selector, Box::new(CompletedSelector{completed})]});
+                    }
+                    if let Some(path) = &pattern_file {
+                        match patterns_from_file(Path::new(path)) {
+                            Ok(patterns) => selector = Box::new(AndSelector{selectors: vec![selector, Box::new(patterns)]}),
+                            Err(error) => {
+                                println!("Error reading patterns: {}", error);
+                                process::exit(1);
+                            }
+                        }
+                    }
+                    let sort_by_completion = completed_between.is_some();
+                    if let Some(between) = completed_between {
+                        selector = Box::new(AndSelector{selectors: vec![selector, Box::new(between)]});
+                    }
+                    if let Some(tagged) = tagged {
+                        selector = Box::new(AndSelector{selectors: vec![
+                            selector, Box::new(TaggedSelector{tagged})]});
+                    }
+                    let render = |tasks: &Tasks| {
+                        let mut selected = tasks.select(&*selector, DoneHandling::Show);
+                        if sort_by_completion {
+                            selected.sort_by_key(|task| tasks.tasks[*task].completed);
+                        }
+                        if let Some(field) = &field {
+                            for task in selected {
+                                println!("{}", tasks.tasks[task].field(field).unwrap_or_default());
+                            }
+                            return;
+                        }
+                        if group_by_tag && matches!(view.format, OutputFormat::Text) {
+                            return tasks.print_grouped_by_tag(&selected, &view);
+                        }
+                        if !explain {
+                            return tasks.print_tasks(&selected, &view);
+                        }
+                        for task in selected {
+                            tasks.print_task(task, &view);
+                            if let Some(reason) = selector.explain(tasks, task) {
+                                println!("  matched: {}", reason);
+                            }
+                        }
+                    };
+                    render(&tasks);
+                    if watch {
+                        // Runs until interrupted, the list is never modified.
+                        if let Err(error) = watch_file(&tasks_file, &settings, |tasks| {
+                            if !append {
+                                print!("{}", CLEAR_SCREEN);
+                            }
+                            render(tasks);
+                        }) {
+                            println!("Error watching tasks: {}", error);
+                        }
+                        return;
+                    }
+                }
+                "label" => {
+                    match (args.get(1).map(|a| a.as_str()), args.get(2), args.get(3..).map(|a| a.join(" "))) {
+                        (Some(action @ ("add" | "remove")), Some(selector), Some(label))
+                                if !label.is_empty() && !label.contains(LABEL_SEPARATOR) => {
+                            for task in tasks.select(
+                                    &*parse_selector(&config.filters, &tasks, selector, EmptyBehaviour::SelectLast),
+                                    DoneHandling::Show) {
+                                let result = match action {
+                                    "add" => tasks.label(task, &label),
+                                    _ => tasks.unlabel(task, &label),
+                                };
+                                if let Err(error) = result {
+                                    println!("Error labeling task: {error}")
+                                }
+                            }
+                        }
+                        _ => println!("Usage: td label add|remove <selector> <label>"),
+                    }
+                }
+                "nth" | "first" => {
+                    let position = match action.as_str() {
+                        "first" => Some(1),
+                        _ => rest.parse::<usize>().ok(),
+                    };
+                    let active = tasks.select(&AllSelector{}, DoneHandling::Hide);
+                    match position.and_then(|p| p.checked_sub(1)).and_then(|p| active.get(p)) {
+                        Some(task) => tasks.print_task(*task, &view),
+                        None => process::exit(1),
+                    }
+                }
+                "stale" => {
+                    let days = match rest.as_str() {
+                        "" => DEFAULT_STALE_DAYS,
+                        days => match days.parse() {
+                            Ok(days) => days,
+                            Err(_) => {
+                                println!("Invalid number of days: {}", days);
+                                process::exit(1);
                             }
                         },
+                    };
+                    tasks.print_tasks(&tasks.stale(DateTime::from(Local::now()), days), &view);
+                }
+                "move-up" | "move-down" => {
+                    match tasks.select(
+                            &*parse_selector(&config.filters, &tasks, rest, EmptyBehaviour::SelectLast),
+                            DoneHandling::Hide).first() {
+                        Some(task) => {
+                            let result = match action.as_str() {
+                                "move-up" => tasks.move_up(*task),
+                                _ => tasks.move_down(*task),
+                            };
+                            match result {
+                                Ok(()) => tasks.status(&view),
+                                Err(error) => println!("Error moving task: {error}"),
+                            }
+                        }
                         None => println!("Task not found"),
                     }
                 }
-                "show" => tasks.select(
-                        &*selector_from_string(rest, EmptyBehaviour::SelectAll),
-                        DoneHandling::Show)
-                        .iter().for_each(|t| tasks.print_task(*t)),
+                "up" | "down" => {
+                    let delta = if action == "up" { 1 } else { -1 };
+                    for task in tasks.select(
+                            &*parse_selector(&config.filters, &tasks, rest, EmptyBehaviour::SelectLast),
+                            DoneHandling::Hide) {
+                        match tasks.nudge_priority(task, delta) {
+                            Ok(priority) => println!("Priority of {} is now {}", tasks.tasks[task], priority),
+                            Err(error) => println!("Error changing priority: {error}"),
+                        }
+                    }
+                }
+                "archive" => {
+                    let before = before.as_deref().map(|date| parse_datetime(date).unwrap_or_else(|| {
+                        println!("Invalid date: {}", date);
+                        process::exit(1);
+                    }));
+                    let archive_file = archive_file(&tasks_file);
+                    let mut archive = Tasks::new();
+                    match archive.load(&archive_file, &settings) {
+                        Ok(0) | Err(TaskFileError::NotFound) => {}
+                        Ok(skipped) => {
+                            println!("Not changing the archive, it has {} malformed tasks", skipped);
+                            process::exit(1);
+                        }
+                        Err(error) => {
+                            println!("Error loading archive: {}", error);
+                            process::exit(1);
+                        }
+                    }
+                    let count = archive.merge(tasks.take_completed_if(|task|
+                        before.is_none_or(|before| task.completed.is_some_and(|completed| completed < before))), false);
+                    // The tasks stay in the list as well, so they would be
+                    // archived again next time.
+                    if no_save {
+                        println!("Would archive {} tasks", count);
+                        return;
+                    }
+                    if let Err(error) = archive.save(&archive_file, &settings) {
+                        println!("Error saving archive: {}", error);
+                        process::exit(1);
+                    }
+                    println!("Archived {} tasks", count);
+                }
+                "prune-archive" => {
+                    let Some(age) = older_than.as_deref().and_then(parse_age) else {
+                        println!("Usage: td prune-archive --older-than <days>d|<weeks>w");
+                        process::exit(1);
+                    };
+                    let archive_file = archive_file(&tasks_file);
+                    let mut archive = Tasks::new();
+                    match archive.load(&archive_file, &settings) {
+                        Ok(0) => {}
+                        Ok(skipped) => {
+                            println!("Not changing the archive, it has {} malformed tasks", skipped);
+                            process::exit(1);
+                        }
+                        Err(error) => {
+                            println!("Error loading archive: {}", error);
+                            process::exit(1);
+                        }
+                    }
+                    let count = archive.prune(DateTime::from(Local::now()) - age);
+                    if no_save {
+                        println!("Would prune {} archived tasks", count);
+                        return;
+                    }
+                    match archive.save(&archive_file, &settings) {
+                        Ok(()) => println!("Pruned {} archived tasks", count),
+                        Err(error) => println!("Error saving archive: {}", error),
+                    }
+                    return;
+                }
+                "status" => match (completed, args[1..].iter().any(|arg| arg == "--all")) {
+                    (Some(true), _) => tasks.status_with(&view, DoneHandling::Only),
+                    (None, true) => tasks.status_with(&view, DoneHandling::Show),
+                    _ => tasks.status(&view),
+                },
+                "bump" => {
+                    let selected = tasks.select(
+                            &*parse_selector(&config.filters, &tasks, rest, EmptyBehaviour::SelectLast),
+                            DoneHandling::Hide);
+                    tasks.bump(&selected);
+                    tasks.status(&view);
+                }
+                "due" => {
+                    let due = match args.get(2..).map(|date| date.join(" ")).filter(|date| !date.is_empty()).as_deref() {
+                        Some("none") => None,
+                        Some(date) => match parse_datetime(date) {
+                            Some(date) => Some(date),
+                            None => {
+                                println!("Invalid date: {}", date);
+                                process::exit(1);
+                            }
+                        },
+                        None => {
+                            println!("Usage: td due <selector> <date>|none");
+                            process::exit(1);
+                        }
+                    };
+                    for task in tasks.select(
+                            &*parse_selector(&config.filters, &tasks, &args[1], EmptyBehaviour::SelectLast),
+                            DoneHandling::Hide) {
+                        if let Err(error) = tasks.set_due(task, due) {
+                            println!("Error setting due date: {error}")
+                        }
+                    }
+                }
+                "set" => {
+                    match (args.get(1), args.get(2), args.get(3..).map(|a| a.join(" "))) {
+                        (Some(field), Some(_), Some(_)) if !SETTABLE_FIELDS.contains(&field.as_str()) => {
+                            println!("Unknown field {}, expected one of {}", field, SETTABLE_FIELDS.join(", "));
+                            process::exit(1);
+                        }
+                        (Some(field), Some(selector), Some(value)) if !value.is_empty() => {
+                            for task in tasks.select(
+                                    &*parse_selector(&config.filters, &tasks, selector, EmptyBehaviour::SelectLast),
+                                    DoneHandling::Show) {
+                                if let Err(error) = tasks.set(task, field, &value) {
+                                    println!("Error setting {}: {}", field, error);
+                                    process::exit(1);
+                                }
+                            }
+                        }
+                        _ => println!("Usage: td set <field> <selector> <value>"),
+                    }
+                }
+                "reword" => {
+                    match (args.get(1), args.get(2..).map(|a| a.join(" "))) {
+                        (Some(selector), Some(text)) if !text.trim().is_empty() => {
+                            match tasks.select(
+                                    &*parse_selector(&config.filters, &tasks, selector, EmptyBehaviour::SelectLast),
+                                    DoneHandling::Show).first() {
+                                Some(task) => if let Err(error) = tasks.reword(*task, text, config.keep_history) {
+                                    println!("Error rewording task: {error}")
+                                },
+                                None => println!("Task not found"),
+                            }
+                        }
+                        _ => println!("Usage: td reword <selector> <new text>"),
+                    }
+                }
+                "peek" => {
+                    let done = match completed {
+                        Some(true) => DoneHandling::Only,
+                        Some(false) => DoneHandling::Hide,
+                        None => DoneHandling::Show,
+                    };
+                    for task in tasks.select(
+                            &*parse_selector(&config.filters, &tasks, rest, EmptyBehaviour::SelectLast), done) {
+                        tasks.print_task(task, &view);
+                        for previous in tasks.tasks[task].history.iter().rev() {
+                            println!("  was: {}", previous);
+                        }
+                    }
+                }
+                "dedupe" => {
+                    let count = tasks.dedupe();
+                    println!("Removed {} duplicate tasks", count);
+                }
+                "touch" => {
+                    let time = DateTime::from(Local::now());
+                    for task in tasks.select(
+                            &*parse_selector(&config.filters, &tasks, rest, EmptyBehaviour::SelectLast),
+                            DoneHandling::Show) {
+                        if let Err(error) = tasks.touch(task, time) {
+                            println!("Error touching task: {error}")
+                        }
+                    }
+                }
+                "replay" => {
+                    if let Err(error) = print_actions(&tasks_file.with_file_name("actions.log")) {
+                        println!("Error reading actions: {}", error);
+                    }
+                    return;
+                }
+                "doctor" => {
+                    let yes_no = |value: bool| if value { "yes" } else { "no" };
+                    println!("td version: {}", env!("CARGO_PKG_VERSION"));
+                    match &base_dirs {
+                        Some(base_dirs) => println!("Data folder: {}", base_dirs.data_local_dir().join("td-todo").display()),
+                        None => println!("Data folder: unknown, no home folder found"),
+                    }
+                    match &config_file {
+                        Some(config_file) => println!("Config file: {} (exists: {})",
+                            config_file.display(), yes_no(config_file.exists())),
+                        None => println!("Config file: unknown, no home folder found"),
+                    }
+                    println!("List: {}", list);
+                    println!("Task file: {}", tasks_file.display());
+                    println!("Exists: {}", yes_no(tasks_file.exists()));
+                    // Opening the file for appending doesn't change it.
+                    println!("Writable: {}", yes_no(match tasks_file.exists() {
+                        true => fs::OpenOptions::new().append(true).open(&tasks_file).is_ok(),
+                        false => tasks_file.parent().and_then(|folder| fs::metadata(folder).ok())
+                            .is_some_and(|folder| !folder.permissions().readonly()),
+                    }));
+                    let mut loaded = Tasks::new();
+                    match loaded.load(&tasks_file, &FileSettings{strict: true, progress: false, verbose: false, ..settings}) {
+                        Ok(_) => println!("Loading: {} tasks", loaded.tasks.len()),
+                        Err(error) => println!("Loading: {}", error),
+                    }
+                    return;
+                }
+                "check" => {
+                    match check_file(&tasks_file, &settings) {
+                        Ok(problems) if problems.is_empty() => println!("No problems found"),
+                        Ok(problems) => {
+                            problems.iter().for_each(|problem| println!("{}", problem));
+                            println!("Found {} problems", problems.len());
+                            process::exit(1);
+                        }
+                        Err(error) => {
+                            println!("Error checking tasks: {}", error);
+                            process::exit(1);
+                        }
+                    }
+                    return;
+                }
+                "rename-list" => {
+                    let folder = data_folder(&base_dirs);
+                    match (args.get(1).and_then(|name| list_file(&folder, name)),
+                            args.get(2).and_then(|name| list_file(&folder, name))) {
+                        (Some(from), Some(_)) if !from.exists() => println!("List {} doesn't exist", args[1]),
+                        (Some(_), Some(to)) if to.exists() => println!("List {} already exists", args[2]),
+                        (Some(from), Some(to)) => match fs::rename(from, to) {
+                            Ok(()) => println!("Renamed list {} to {}", args[1], args[2]),
+                            Err(error) => println!("Error renaming list: {}", error),
+                        },
+                        _ => println!("Usage: td rename-list <old name> <new name>"),
+                    }
+                    // Don't save the list under its old name again.
+                    return;
+                }
+                "delete-list" => {
+                    match args.get(1).and_then(|name| list_file(&data_folder(&base_dirs), name)) {
+                        Some(file) if !file.exists() => println!("List {} doesn't exist", rest),
+                        Some(file) => {
+                            if !yes && !confirm(&format!("Delete list {}?", rest)) {
+                                println!("Aborted");
+                            } else if let Err(error) = fs::remove_file(file) {
+                                println!("Error deleting list: {}", error);
+                            } else {
+                                println!("Deleted list {}", rest);
+                            }
+                        }
+                        None => println!("Usage: td delete-list <name>"),
+                    }
+                    return;
+                }
+                "log" => {
+                    if rest.trim().is_empty() {
+                        println!("Can't create an empty task");
+                        process::exit(1);
+                    }
+                    let time = at.unwrap_or_else(|| DateTime::from(Local::now()));
+                    let position = tasks.tasks.len();
+                    tasks.create(Task::new(rest.clone(), time), position);
+                    match tasks.complete(position, time) {
+                        Ok(()) => println!("Completed {}!", tasks.tasks[position].text),
+                        Err(error) => println!("Error completing task: {error}"),
+                    }
+                }
+                "diff" => {
+                    let mut other = Tasks::new();
+                    match other.load(Path::new(rest), &settings) {
+                        Ok(_) => {
+                            let changes = tasks.diff(&other);
+                            if changes.is_empty() {
+                                println!("No differences");
+                            }
+                            changes.iter().for_each(|change| println!("{}", change));
+                        }
+                        Err(error) => println!("Error loading {}: {}", rest, error),
+                    }
+                }
+                "export" => {
+                    match rest.as_str() {
+                        "markdown" => print!("{}", tasks.to_markdown(grouped)),
+                        "ics" => print!("{}", tasks.to_ics()),
+                        "html" => print!("{}", tasks.to_html()),
+                        "json" if envelope => match view.pretty {
+                            true => println!("{:#}", tasks.to_json_envelope()),
+                            false => println!("{}", tasks.to_json_envelope()),
+                        },
+                        "json" => {
+                            let all: Vec<usize> = (0..tasks.tasks.len()).collect();
+                            tasks.print_tasks(&all, &View{format: OutputFormat::Json, ..view});
+                        }
+                        "csv" => {
+                            let fields: Vec<&str> = match (&columns, all_fields) {
+                                (Some(columns), _) => columns.split(',').map(str::trim).collect(),
+                                (None, true) => FIELDS.to_vec(),
+                                (None, false) => vec!["text", "created", "completed"],
+                            };
+                            if let Some(field) = fields.iter().find(|field| !FIELDS.contains(field)) {
+                                println!("Unknown field {}, expected one of {}", field, FIELDS.join(", "));
+                                process::exit(1);
+                            }
+                            if let Err(error) = tasks.export_csv(&fields, io::stdout()) {
+                                println!("Error exporting tasks: {}", error);
+                            }
+                        }
+                        _ => {
+                            println!("Usage: td export markdown|json|csv|ics|html");
+                            process::exit(1);
+                        }
+                    }
+                    return;
+                }
+                "edit" if editor => {
+                    match edit_in_editor(&tasks.to_editable()) {
+                        Ok(content) => match tasks.apply_edit(&content, config.keep_history) {
+                            Ok(()) => tasks.status(&view),
+                            Err(error) => {
+                                println!("{}, the tasks were not changed", error);
+                                process::exit(1);
+                            }
+                        },
+                        Err(error) => {
+                            println!("Error editing tasks: {}", error);
+                            process::exit(1);
+                        }
+                    }
+                }
+                "edit" => println!("Usage: td edit --editor"),
+                "labels" => tasks.labels().iter().for_each(|label| println!("{}", label)),
+                "stats" => {
+                    let week_start = match &config.week_start {
+                        Some(day) => match day.parse::<Weekday>() {
+                            Ok(day) => day,
+                            Err(_) => {
+                                println!("Invalid week_start {}, expected a day like monday", day);
+                                process::exit(1);
+                            }
+                        },
+                        None => Weekday::Mon,
+                    };
+                    let per_day = tasks.completions_per_day();
+                    if csv {
+                        let (heading, rows) = match weekly {
+                            true => ("week", tasks.completions_per_week(week_start)),
+                            false => ("date", per_day),
+                        };
+                        println!("{},completed", heading);
+                        for (day, count) in rows {
+                            println!("{},{}", day, count);
+                        }
+                    } else {
+                        let (active, completed) = tasks.counts();
+                        println!("{} tasks, {} active, {} completed", tasks.tasks.len(), active, completed);
+                        let this_week = start_of_week(Local::now().naive_local().date(), week_start);
+                        println!("Completed this week: {}", per_day.iter()
+                            .filter(|(day, _)| *day >= this_week).map(|(_, count)| count).sum::<usize>());
+                        if let Some((day, count)) = per_day.iter().max_by_key(|(_, count)| *count) {
+                            println!("Most completed on one day: {} on {}", count, day);
+                        }
+                    }
+                }
                 _ => {
-                    for text in args.join(" ").split(",") {
-                        tasks.create(Task::from_string(text.to_string()));
+                    let Some(text) = task_text(&args, rest) else {
+                        println!("Unknown option: {}", action);
+                        process::exit(1);
+                    };
+                    let position = match (&after, &before) {
+                        (Some(id), _) | (_, Some(id)) => {
+                            match id.trim_start_matches('#').parse().ok().and_then(|id| tasks.find_id(id)) {
+                                Some(position) if after.is_some() => position + 1,
+                                Some(position) => position,
+                                None => {
+                                    println!("No task with id {}", id);
+                                    process::exit(1);
+                                }
+                            }
+                        }
+                        (None, None) => 0,
+                    };
+                    let template = match &template {
+                        Some(name) => match config.templates.get(name) {
+                            Some(template) => template.as_str(),
+                            None => {
+                                println!("Unknown template: {}", name);
+                                process::exit(1);
+                            }
+                        },
+                        None => "",
+                    };
+                    // Pasted checklists have one task per line.
+                    let texts: Vec<&str> = match multiline {
+                        true => text.lines().map(str::trim).filter(|line| !line.is_empty()).collect(),
+                        false => text.split(',').collect(),
+                    };
+                    // Blank tasks can't be selected by patterns and are
+                    // usually caused by stray commas.
+                    if texts.is_empty() || texts.iter().any(|text| text.trim().is_empty()) {
+                        println!("Can't create an empty task");
+                        process::exit(1);
+                    }
+                    // Tasks added as done in the past were created by then
+                    // at the latest, like with `td log`.
+                    let created = match (created, at) {
+                        (None, Some(at)) if done => Some(at),
+                        (created, _) => created,
+                    };
+                    if let (true, Some(created), Some(at)) = (done, created, at) {
+                        if at < created {
+                            println!("{}", TaskError::CompletedBeforeCreated);
+                            process::exit(1);
+                        }
                     }
-                    tasks.status();
+                    for text in texts {
+                        let text = format!("{}{}", template, text);
+                        let mut task = match created {
+                            Some(created) => Task::new(text, created),
+                            None => Task::from_string(text),
+                        };
+                        task.priority = priority.unwrap_or(0);
+                        task.due = due;
+                        tasks.create(task, position);
+                        if done {
+                            match tasks.complete(position, at.unwrap_or_else(|| DateTime::from(Local::now()))) {
+                                Ok(()) => println!("Completed {}!", tasks.tasks[position].text),
+                                Err(error) => println!("Error completing task: {error}"),
+                            }
+                        }
+                    }
+                    tasks.status(&view);
                 }
             }
         }
-        None => tasks.status()
+        None if completed == Some(true) => tasks.status_with(&view, DoneHandling::Only),
+        None => tasks.status(&view),
+    }
+    // Saving would rewrite the file, for example by upgrading its format.
+    if !no_save && tasks.dirty
+            && args.first().is_some_and(|action| !READ_ONLY_COMMANDS.contains(&action.as_str())) {
+        let sink = sink && args.first().is_some_and(|action| action == "done");
+        save_tasks(tasks, &tasks_file, &settings, stored_order, sink,
+            loaded.as_ref().map(|loaded| (loaded, command.as_str())));
+    }
+    if let (Some(minutes), Some(id)) = (timer, timed_task) {
+        countdown(minutes);
+        if !confirm("Mark the task as done?") {
+            return;
+        }
+        let mut tasks = Tasks::new();
+        match tasks.load(&tasks_file, &settings) {
+            Ok(0) => {}
+            Ok(skipped) => {
+                println!("Not changing the tasks, the file has {} malformed tasks", skipped);
+                return;
+            }
+            Err(error) => {
+                println!("Error loading tasks: {}", error);
+                return;
+            }
+        }
+        let loaded = config.audit.then(|| tasks.tasks.iter().cloned().collect::<Tasks>());
+        match tasks.find_id(id).map(|task| tasks.complete(task, DateTime::from(Local::now())).map(|_| task)) {
+            Some(Ok(task)) => println!("Completed {}!", tasks.tasks[task].text),
+            Some(Err(error)) => println!("Error completing task: {error}"),
+            None => println!("Task not found"),
+        }
+        if !no_save && tasks.dirty {
+            let command = format!("done #{}", id);
+            save_tasks(tasks, &tasks_file, &settings, None, sink, loaded.as_ref().map(|loaded| (loaded, command.as_str())));
+        }
+    }
+}
+
+/// Saves the tasks, restoring the order of the file if they were sorted and
+/// moving completed tasks to the end with `sink`. With `audit`, the changes
+/// compared to the loaded tasks are recorded in the audit log.
+fn save_tasks(mut tasks: Tasks, tasks_file: &Path, settings: &FileSettings, stored_order: Option<Vec<u32>>,
+        sink: bool, audit: Option<(&Tasks, &str)>) {
+    if let Some(ids) = stored_order {
+        tasks.restore_order(&ids);
+    }
+    // Completed tasks have to be sunk again after restoring the order.
+    if sink {
+        tasks.sink_completed();
+    }
+    if let Some((loaded, command)) = audit {
+        let ids: Vec<u32> = tasks.diff(loaded).iter().map(|change| match change {
+            Change::Added(task) | Change::Removed(task) | Change::Changed(_, task) => task.id,
+        }).collect();
+        if let Err(error) = record_action(&tasks_file.with_file_name("actions.log"), command, &ids) {
+            println!("Error recording action: {}", error);
+        }
     }
-    if let Err(error) = tasks.save(&tasks_file) {
+    if let Err(error) = tasks.save(tasks_file, settings) {
         println!("Error saving tasks: {}", error)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tilde_expands_to_the_home_folder() {
+        let home = Path::new("/home/user");
+        assert_eq!(expand_path("~/foo", Some(home)), PathBuf::from("/home/user/foo"));
+        assert_eq!(expand_path("~", Some(home)), PathBuf::from("/home/user"));
+        assert_eq!(expand_path("~other/foo", Some(home)), PathBuf::from("~other/foo"));
+        assert_eq!(expand_path("~/foo", None), PathBuf::from("~/foo"));
+    }
+
+    #[test]
+    fn unknown_options_add_no_task() {
+        let args = |line: &str| line.split(' ').map(String::from).collect::<Vec<_>>();
+        assert_eq!(task_text(&args("--hlep me"), "me"), None);
+        assert_eq!(task_text(&args("-x"), ""), None);
+        assert_eq!(task_text(&args("add -x"), "-x"), Some("-x".to_string()));
+        assert_eq!(task_text(&args("buy milk"), "milk"), Some("buy milk".to_string()));
+    }
+}